@@ -0,0 +1,611 @@
+use std::future::Future;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use crate::block::{BlockDecoder, BlockError};
+use crate::command::TRIGgerCommand::{SWEep, TRIGgerState};
+use crate::command::WAVeformCommand::{self, ConvertData, Format, MaxMemorySize, MaxTransferSize, MemoryDepth, Mode, Preamble, Source, WAVeformState};
+use crate::command::CHANnelCommand;
+use crate::device;
+use crate::number;
+use crate::property::PropertyValue;
+use crate::error::{Error, Result};
+use crate::firmware::{self, Quirk, QuirkRegistry};
+use crate::identity::Identity;
+use crate::model::Capabilities;
+use crate::session::{Sentinel, MAX_STALE_LINES};
+
+// The tokio counterpart of `Visa`, `Ds1000z` and the trigger/waveform commands. Response
+// parsing, range checks and voltage conversion are shared with the blocking API; only
+// the I/O is async.
+
+pub trait AsyncVisa: Send {
+    fn write_scip_cmd(&mut self, buf: &[u8]) -> impl Future<Output = Result<()>> + Send;
+    fn read_result(&mut self) -> impl Future<Output = Result<String>> + Send;
+    fn read_block(&mut self) -> impl Future<Output = Result<Vec<u8>>> + Send;
+
+    // See `Visa::discard`.
+    fn discard(&mut self, _len: usize) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+}
+
+impl<T: AsyncBufRead + AsyncWrite + Unpin + Send> AsyncVisa for T {
+    async fn write_scip_cmd(&mut self, buf: &[u8]) -> Result<()> {
+        self.write_all(buf).await?;
+        self.flush().await?;
+        Ok(())
+    }
+
+    async fn read_result(&mut self) -> Result<String> {
+        let mut line = Vec::new();
+        self.read_until(b'\n', &mut line).await?;
+        if line.last() != Some(&b'\n') {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed before the response terminator").into());
+        }
+        device::response_text(line)
+    }
+
+    async fn read_block(&mut self) -> Result<Vec<u8>> {
+        Ok(read_block(self).await?)
+    }
+
+    async fn discard(&mut self, len: usize) -> Result<()> {
+        let mut skipped = vec![0; len];
+        self.read_exact(&mut skipped).await?;
+        Ok(())
+    }
+}
+
+// Async `block::read_block`: the payload, with the terminator consumed.
+pub async fn read_block<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Vec<u8>, BlockError> {
+    let mut decoder = BlockDecoder::new();
+    while !decoder.is_done() {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            return Err(decoder.end_of_stream());
+        }
+        let used = decoder.feed(available)?;
+        reader.consume(used);
+    }
+    Ok(decoder.into_payload())
+}
+
+pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<tokio::io::BufStream<TcpStream>> {
+    let stream = TcpStream::connect(addr).await?;
+    stream.set_nodelay(true)?;
+    Ok(tokio::io::BufStream::with_capacity(64 * 1024, 64 * 1024, stream))
+}
+
+// Cooperative cancellation for long transfers. `get_data` checks it between chunks, so
+// the transport is never left in the middle of a block. A `get_data` future dropped
+// mid-transfer does leave the rest of a block behind; call `AsyncDs1000z::resync`
+// before using the connection again.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug)]
+pub struct AsyncDs1000z<V: AsyncVisa> {
+    device: V,
+    trigger_state: TRIGgerState,
+    waveform_state: WAVeformState,
+    timeout: Option<Duration>,
+    sentinel: u8,
+    identity: Option<Identity>,
+    quirk_registry: QuirkRegistry,
+    quirks: Vec<Quirk>,
+}
+
+impl<V: AsyncVisa> AsyncDs1000z<V> {
+    // Identifies the scope first, see `Ds1000z::new`.
+    pub async fn new(device: V) -> Result<AsyncDs1000z<V>> {
+        AsyncDs1000z::with_quirks(device, QuirkRegistry::new()).await
+    }
+
+    // See `Ds1000z::with_quirks`.
+    pub async fn with_quirks(device: V, registry: QuirkRegistry) -> Result<AsyncDs1000z<V>> {
+        let mut scope = AsyncDs1000z {
+            device,
+            trigger_state: TRIGgerState::default(),
+            waveform_state: WAVeformState::new(),
+            timeout: Some(Duration::from_secs(10)),
+            sentinel: 0,
+            identity: None,
+            quirk_registry: registry,
+            quirks: Vec::new(),
+        };
+        scope.identity().await?;
+        scope.trigger().get_sweep().await?;
+        scope.waveform().init().await?;
+        Ok(scope)
+    }
+
+    pub fn trigger(&mut self) -> AsyncTRIGgerCommand<'_, V> {
+        AsyncTRIGgerCommand { scope: self }
+    }
+
+    pub fn waveform(&mut self) -> AsyncWAVeformCommands<'_, V> {
+        AsyncWAVeformCommands { scope: self }
+    }
+
+    // Limit for each response; `None` waits forever.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    async fn with_timeout<T>(timeout: Option<Duration>, read: impl Future<Output = Result<T>>) -> Result<T> {
+        match timeout {
+            Some(limit) => tokio::time::timeout(limit, read).await
+                .map_err(|_| Error::Timeout(io::Error::new(io::ErrorKind::TimedOut, format!("no response within {:?}", limit))))?,
+            None => read.await,
+        }
+    }
+
+    pub async fn write(&mut self, command: &str) -> Result<()> {
+        let mut buf = Vec::with_capacity(command.len() + 1);
+        buf.extend_from_slice(command.as_bytes());
+        buf.push(b'\n');
+        self.device.write_scip_cmd(&buf).await
+    }
+
+    pub async fn query(&mut self, command: &str) -> Result<String> {
+        self.write(command).await?;
+        let result = Self::with_timeout(self.timeout, self.device.read_result()).await;
+        self.recover(result).await
+    }
+
+    pub async fn query_block(&mut self, command: &str) -> Result<Vec<u8>> {
+        self.write(command).await?;
+        let result = Self::with_timeout(self.timeout, self.device.read_block()).await;
+        self.recover(result).await
+    }
+
+    // A reply that timed out may still arrive, the rest of a block included, and one
+    // that is out of step means earlier replies are in the pipe; either way the
+    // connection is brought back in step before the error is returned. Unlike
+    // `Ds1000z`, nothing is retried.
+    async fn recover<T>(&mut self, result: Result<T>) -> Result<T> {
+        if let Err(Error::Timeout(_) | Error::BlockError(BlockError::InvalidHeader(_) | BlockError::MissingTerminator(Some(_)))) = &result {
+            self.resync().await?;
+        }
+        result
+    }
+
+    // See `Ds1000z::resync`.
+    pub async fn resync(&mut self) -> Result<()> {
+        self.sentinel = self.sentinel % 254 + 2;
+        let mut sentinel = Sentinel::new(self.sentinel);
+        for command in sentinel.commands() {
+            self.write(&command).await?;
+        }
+        for _ in 0..MAX_STALE_LINES {
+            let line = match Self::with_timeout(self.timeout, self.device.read_result()).await {
+                Ok(line) => Some(line),
+                Err(Error::ProtocolError(_)) => None,
+                Err(err) => return Err(err),
+            };
+            if let Some(mask) = sentinel.scan(line.as_deref()) {
+                return self.write(&format!("*ESE {}", mask)).await;
+            }
+        }
+        Err(Error::ProtocolError(format!("no sentinel after discarding {} replies", MAX_STALE_LINES)))
+    }
+
+    // Asks `*IDN?` on first use.
+    pub async fn identity(&mut self) -> Result<&Identity> {
+        if self.identity.is_none() {
+            let identity = Identity::parse(&self.query("*IDN?").await?)?;
+            self.quirks = self.quirk_registry.quirks_for(&identity);
+            self.identity = Some(identity);
+        }
+        Ok(self.identity.as_ref().unwrap())
+    }
+
+    // See `Ds1000z::quirks`.
+    pub fn quirks(&self) -> &[Quirk] {
+        &self.quirks
+    }
+
+    // See `Ds1000z::analog_channels`.
+    async fn analog_channels(&mut self) -> Result<u8> {
+        Ok(self.identity().await?.capabilities().map_or(4, |capabilities| capabilities.analog_channels))
+    }
+
+    // See `Ds1000z::max_memory_depth`.
+    async fn max_memory_depth(&mut self) -> Result<u32> {
+        Ok(self.identity().await?.capabilities().map_or(MemoryDepth::DS1102Z_E as u32, Capabilities::max_memory_depth))
+    }
+
+    pub fn device(&mut self) -> &mut V {
+        &mut self.device
+    }
+
+    pub fn into_inner(self) -> V {
+        self.device
+    }
+}
+
+pub struct AsyncTRIGgerCommand<'a, V: AsyncVisa> {
+    scope: &'a mut AsyncDs1000z<V>,
+}
+
+impl<V: AsyncVisa> AsyncTRIGgerCommand<'_, V> {
+    pub fn state(&self) -> &TRIGgerState {
+        &self.scope.trigger_state
+    }
+
+    pub async fn set_sweep(&mut self, sweep: SWEep) -> Result<()> {
+        self.scope.write(&format!(":TRIGger:SWEep {}", sweep)).await
+    }
+
+    pub async fn get_sweep(&mut self) -> Result<SWEep> {
+        let sweep: SWEep = self.scope.query(":TRIGger:SWEep?").await?.parse()?;
+        self.scope.trigger_state.sweep = sweep;
+        Ok(sweep)
+    }
+
+    pub async fn sweep(&mut self, sweep: SWEep) -> Result<SWEep> {
+        self.set_sweep(sweep).await?;
+        self.get_sweep().await
+    }
+}
+
+pub struct AsyncWAVeformCommands<'a, V: AsyncVisa> {
+    scope: &'a mut AsyncDs1000z<V>,
+}
+
+impl<V: AsyncVisa> Deref for AsyncWAVeformCommands<'_, V> {
+    type Target = WAVeformState;
+
+    fn deref(&self) -> &WAVeformState {
+        &self.scope.waveform_state
+    }
+}
+
+impl<V: AsyncVisa> DerefMut for AsyncWAVeformCommands<'_, V> {
+    fn deref_mut(&mut self) -> &mut WAVeformState {
+        &mut self.scope.waveform_state
+    }
+}
+
+impl<V: AsyncVisa> AsyncWAVeformCommands<'_, V> {
+    async fn init(&mut self) -> Result<()> {
+        self.get_preamble().await?;
+        self.get_mode().await?;
+        self.get_source().await?;
+        self.get_format().await?;
+        self.start(1).await?;
+        self.stop(1).await?;
+        Ok(())
+    }
+
+    async fn query_f32(&mut self, command: &str) -> Result<f32> {
+        Ok(number::parse_number(&self.scope.query(command).await?)? as f32)
+    }
+
+    pub async fn set_source(&mut self, source: Source) -> Result<()> {
+        self.scope.write(&format!(":WAVeform:SOURce {}", source)).await
+    }
+
+    pub async fn get_source(&mut self) -> Result<Source> {
+        self.scope.query(":WAVeform:SOURce?").await?.parse()
+    }
+
+    pub async fn set_mode(&mut self, mode: Mode) -> Result<()> {
+        self.scope.write(&format!(":WAVeform:MODE {}", mode)).await
+    }
+
+    pub async fn get_mode(&mut self) -> Result<Mode> {
+        let mode: Mode = self.scope.query(":WAVeform:MODE?").await?.parse()?;
+        if mode != Mode::NORM {
+            self.get_memory_depth().await?;
+        }
+        let sweep = self.scope.trigger_state.sweep;
+        self.apply_mode(mode, sweep)?;
+        Ok(mode)
+    }
+
+    pub async fn mode(&mut self, mode: Mode) -> Result<()> {
+        self.set_mode(mode).await?;
+        self.get_mode().await?;
+        Ok(())
+    }
+
+    // The sizes as of the last `get_mode` and `get_format`.
+    pub fn max_memory_size(&self) -> MaxMemorySize {
+        self.max_memory_size
+    }
+
+    pub fn max_transfer_size(&self) -> MaxTransferSize {
+        self.max_transfer_size
+    }
+
+    pub async fn set_format(&mut self, format: Format) -> Result<()> {
+        self.scope.write(&format!(":WAVeform:FORMat {}", format)).await
+    }
+
+    pub async fn get_format(&mut self) -> Result<Format> {
+        let format: Format = self.scope.query(":WAVeform:FORMat?").await?.parse()?;
+        self.apply_format(format);
+        Ok(format)
+    }
+
+    pub async fn format(&mut self, format: Format) -> Result<()> {
+        self.set_format(format).await?;
+        self.get_format().await?;
+        Ok(())
+    }
+
+    // See `WAVeformCommands::get_memory_depth`.
+    pub async fn get_memory_depth(&mut self) -> Result<u32> {
+        let channels = self.scope.analog_channels().await?;
+        let max_memory_depth = self.scope.max_memory_depth().await?;
+        let depth = self.scope.query(":ACQuire:MDEPth?").await?;
+        let configured = match depth.trim() {
+            "AUTO" => {
+                let sample_rate = number::parse_number(&self.scope.query(":ACQuire:SRATe?").await?)?;
+                let scale = number::parse_number(&self.scope.query(":TIMebase:SCALe?").await?)?;
+                (sample_rate * scale * 12.0).round() as u32
+            }
+            text => firmware::parse_u32(text, self.scope.quirks())?,
+        };
+        let mut enabled_channels = 0;
+        for display in &CHANnelCommand::DISPLAY[..channels as usize] {
+            if bool::parse(&self.scope.query(&format!("{}?", display.header())).await?)? {
+                enabled_channels += 1;
+            }
+        }
+        self.apply_memory_depth(configured, enabled_channels, max_memory_depth);
+        Ok(self.acquisition_points)
+    }
+
+    pub async fn get_preamble(&mut self) -> Result<Preamble> {
+        let preamble = Preamble::parse(&self.scope.query(":WAVeform:PREamble?").await?, self.scope.quirks())?;
+        self.apply_preamble(preamble);
+        Ok(preamble)
+    }
+
+    pub async fn get_origin(&mut self) -> Result<()> {
+        self.origin.x = self.query_f32(":WAVeform:XORigin?").await?;
+        self.origin.y = self.query_f32(":WAVeform:YORigin?").await?;
+        Ok(())
+    }
+
+    pub async fn get_reference(&mut self) -> Result<()> {
+        self.reference.x = self.query_f32(":WAVeform:XREFerence?").await?;
+        self.reference.y = self.query_f32(":WAVeform:YREFerence?").await?;
+        Ok(())
+    }
+
+    pub async fn get_increment(&mut self) -> Result<()> {
+        self.increment.x = self.query_f32(":WAVeform:XINCrement?").await?;
+        self.increment.y = self.query_f32(":WAVeform:YINCrement?").await?;
+        Ok(())
+    }
+
+    pub async fn get_start_point(&mut self) -> Result<u32> {
+        self.start_point = firmware::parse_u32(&self.scope.query(":WAVeform:STARt?").await?, self.scope.quirks())?;
+        Ok(self.start_point)
+    }
+
+    pub async fn set_start_point(&mut self, start_point: u32) -> Result<()> {
+        self.check_start_point(start_point)?;
+        self.scope.write(&format!(":WAVeform:STARt {}", start_point)).await
+    }
+
+    pub async fn start(&mut self, start_point: u32) -> Result<()> {
+        self.set_start_point(start_point).await?;
+        self.get_start_point().await?;
+        Ok(())
+    }
+
+    pub async fn get_stop_point(&mut self) -> Result<u32> {
+        self.stop_point = firmware::parse_u32(&self.scope.query(":WAVeform:STOP?").await?, self.scope.quirks())?;
+        Ok(self.stop_point)
+    }
+
+    pub async fn set_stop_point(&mut self, stop_point: u32) -> Result<()> {
+        self.check_stop_point(stop_point)?;
+        self.scope.write(&format!(":WAVeform:STOP {}", stop_point)).await
+    }
+
+    pub async fn stop(&mut self, stop_point: u32) -> Result<()> {
+        self.set_stop_point(stop_point).await?;
+        self.get_stop_point().await?;
+        Ok(())
+    }
+
+    pub async fn get_data(&mut self) -> Result<()> {
+        let format = self.format;
+        if format == Format::ASC {
+            return Ok(());
+        }
+        let payload = self.scope.query_block(":WAVeform:DATA?").await?;
+        let full_block = format == Format::BYTE && payload.len() == MaxTransferSize::BYTE as usize;
+        let trailing = firmware::trailing_block_bytes(self.scope.quirks(), full_block);
+        if trailing > 0 {
+            AsyncDs1000z::<V>::with_timeout(self.scope.timeout, self.scope.device.discard(trailing)).await?;
+        }
+        match format {
+            Format::BYTE => device::store_bytes_u8(payload, &mut self.data),
+            _ => device::store_bytes_u16(payload, &mut self.data),
+        }
+    }
+}
+
+// Async `get_data`. On cancellation the chunks read so far stay in `convert_data` and
+// `Error::Cancelled` is returned.
+pub async fn get_data<V: AsyncVisa>(range: u32, waveform: &mut AsyncWAVeformCommands<'_, V>, convert_data: &mut ConvertData, cancel: &CancelToken) -> Result<()> {
+    if waveform.mode != Mode::NORM {
+        let source = waveform.get_source().await?;
+        let model = waveform.scope.identity().await?.model.clone();
+        WAVeformCommand::check_source(waveform.mode, source, &model)?;
+    }
+    waveform.check_range(range)?;
+    for (start_n, end_n) in WAVeformCommand::chunks(range, waveform.max_transfer_size) {
+        if cancel.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        waveform.get_preamble().await?;
+        waveform.start(start_n).await?;
+        waveform.stop(end_n).await?;
+        waveform.get_data().await?;
+        convert_data.convert_voltage(waveform)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+    use std::thread;
+    use crate::simulator::{self, Instrument};
+
+    async fn scope(instrument: Instrument) -> AsyncDs1000z<tokio::io::BufStream<TcpStream>> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || simulator::serve(listener, Arc::new(Mutex::new(instrument))));
+        AsyncDs1000z::new(connect(address).await.unwrap()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_async_waveform() {
+        let instrument = Instrument { memory_depth: Some(600000), ..Instrument::default() };
+        let mut scope = scope(instrument.clone()).await;
+        scope.trigger().sweep(SWEep::SING).await.unwrap();
+        let mut convert_data = ConvertData::new();
+        let mut waveform = scope.waveform();
+        waveform.format(Format::BYTE).await.unwrap();
+        waveform.mode(Mode::RAW).await.unwrap();
+        get_data(600000, &mut waveform, &mut convert_data, &CancelToken::new()).await.unwrap();
+        assert_eq!(convert_data.count, 600000);
+        let acquired = Instrument { mode: Mode::RAW, running: false, ..instrument };
+        let expected = acquired.voltage(Source::CHAN1, 4321) as f32;
+        assert!((convert_data.data[4321].y - expected).abs() <= 0.021, "{} != {}", convert_data.data[4321].y, expected);
+    }
+
+    #[tokio::test]
+    async fn test_firmware_quirks() {
+        use crate::firmware::QuirkEntry;
+        let quirks = [Quirk::Nr3Integers, Quirk::TrailingBlockBytes(2)];
+        let instrument = Instrument { memory_depth: Some(300000), quirks: quirks.to_vec(), ..Instrument::default() };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || simulator::serve(listener, Arc::new(Mutex::new(instrument))));
+        let mut registry = QuirkRegistry::new();
+        for quirk in quirks {
+            registry.register(QuirkEntry { model: Some("DS1104Z".to_string()), from: None, until: "00.04.05".parse().ok(), quirk });
+        }
+        let mut scope = AsyncDs1000z::with_quirks(connect(address).await.unwrap(), registry).await.unwrap();
+        assert_eq!(scope.quirks(), quirks);
+        scope.trigger().sweep(SWEep::SING).await.unwrap();
+        let mut convert_data = ConvertData::new();
+        let mut waveform = scope.waveform();
+        waveform.mode(Mode::RAW).await.unwrap();
+        get_data(300000, &mut waveform, &mut convert_data, &CancelToken::new()).await.unwrap();
+        assert_eq!(convert_data.count, 300000);
+        assert_eq!(scope.query("*OPC?").await.unwrap(), "1");
+    }
+
+    #[tokio::test]
+    async fn test_two_channel_memory_depth() {
+        let idn = "RIGOL TECHNOLOGIES,DS1202Z-E,DS1ZE000000001,00.06.02".to_string();
+        let mut instrument = Instrument { idn, ..Instrument::default() };
+        instrument.channels[1].display = true;
+        let mut scope = scope(instrument).await;
+        scope.set_timeout(Some(Duration::from_secs(1)));
+        assert_eq!(scope.waveform().get_memory_depth().await.unwrap(), 12_000_000);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_between_chunks() {
+        let mut scope = scope(Instrument { memory_depth: Some(600000), ..Instrument::default() }).await;
+        scope.trigger().sweep(SWEep::SING).await.unwrap();
+        let mut convert_data = ConvertData::new();
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        let mut waveform = scope.waveform();
+        waveform.format(Format::BYTE).await.unwrap();
+        waveform.mode(Mode::RAW).await.unwrap();
+        assert!(matches!(get_data(600000, &mut waveform, &mut convert_data, &cancel).await, Err(Error::Cancelled)));
+        assert_eq!(convert_data.count, 0);
+        // the connection is still in step
+        assert_eq!(scope.query(":WAV:FORM?").await.unwrap(), "BYTE");
+    }
+
+    #[tokio::test]
+    async fn test_query_timeout() {
+        let (client, _server) = tokio::io::duplex(1024);
+        let mut scope = AsyncDs1000z {
+            device: tokio::io::BufStream::new(client),
+            trigger_state: TRIGgerState::default(),
+            waveform_state: WAVeformState::new(),
+            timeout: Some(Duration::from_millis(50)),
+            sentinel: 0,
+            identity: None,
+            quirk_registry: QuirkRegistry::new(),
+            quirks: Vec::new(),
+        };
+        assert!(matches!(scope.query("*IDN?").await, Err(Error::Timeout(_))));
+    }
+
+    #[tokio::test]
+    async fn test_timeout_mid_block_resyncs() {
+        use tokio::io::AsyncBufReadExt;
+        let (client, server) = tokio::io::duplex(1024);
+        let mut scope = AsyncDs1000z {
+            device: tokio::io::BufStream::new(client),
+            trigger_state: TRIGgerState::default(),
+            waveform_state: WAVeformState::new(),
+            timeout: Some(Duration::from_millis(50)),
+            sentinel: 0,
+            identity: None,
+            quirk_registry: QuirkRegistry::new(),
+            quirks: Vec::new(),
+        };
+        // a scope that sends the rest of the block only after the read timed out
+        let instrument = tokio::spawn(async move {
+            let mut server = tokio::io::BufStream::new(server);
+            let mut commands = Vec::new();
+            let mut line = String::new();
+            while commands.len() < 7 {
+                line.clear();
+                server.read_line(&mut line).await.unwrap();
+                commands.push(line.trim().to_string());
+                match commands.len() {
+                    1 => server.write_all(b"#9000000010abc").await.unwrap(),
+                    5 => server.write_all(b"defg\nhij\n36\n2\n1\n").await.unwrap(),
+                    7 => server.write_all(b"BYTE\n").await.unwrap(),
+                    _ => continue,
+                }
+                server.flush().await.unwrap();
+            }
+            commands
+        });
+        assert!(matches!(scope.query_block(":WAV:DATA?").await, Err(Error::Timeout(_))));
+        assert_eq!(scope.query(":WAV:FORM?").await.unwrap(), "BYTE");
+        assert_eq!(instrument.await.unwrap(), [":WAV:DATA?", "*ESE?", "*ESE 2", "*ESE?", "*OPC?", "*ESE 36", ":WAV:FORM?"]);
+    }
+}
@@ -0,0 +1,202 @@
+use std::marker::PhantomData;
+use crate::device::Visa;
+use crate::error::{Error, Result};
+use crate::firmware::Quirk;
+use crate::property::PropertyValue;
+use crate::session::Ds1000z;
+
+// Several program messages sent as one compound message, e.g.
+//
+//     :WAVeform:XORigin?;:WAVeform:YORigin?
+//
+// which the scope answers with one line of `;` separated replies, so a group of
+// settings costs a single round trip:
+//
+//     let mut batch = scope.batch();
+//     let x = batch.query::<f32>(":WAVeform:XORigin?");
+//     let y = batch.query::<f32>(":WAVeform:YORigin?");
+//     let replies = batch.send()?;
+//     let origin = (replies.get(x)?, replies.get(y)?);
+//
+// Replies are parsed like the settings of a `Property`; one of another type, e.g. a
+// `String`, is read with `text`. Headers are sent absolute (a missing leading ':' is
+// added). Block queries such as `:WAVeform:DATA?` can not be batched.
+
+pub struct Batch<'a, V: Visa> {
+    scope: &'a mut Ds1000z<V>,
+    messages: Vec<String>,
+    queries: usize,
+}
+
+// Refers to the reply of one query in a batch.
+#[derive(Debug)]
+pub struct Reply<T> {
+    index: usize,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for Reply<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Reply<T> {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Replies {
+    replies: Vec<String>,
+    quirks: Vec<Quirk>,
+}
+
+impl Replies {
+    pub fn get<T: PropertyValue>(&self, reply: Reply<T>) -> Result<T> {
+        T::parse_with(self.text(reply)?, &self.quirks)
+    }
+
+    // A `Reply` from another, longer batch is refused.
+    pub fn text<T>(&self, reply: Reply<T>) -> Result<&str> {
+        self.replies.get(reply.index).map(String::as_str).ok_or_else(|| {
+            Error::InvalidArgument(format!("reply {} of a batch with {} replies", reply.index, self.replies.len()))
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.replies.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.replies.is_empty()
+    }
+}
+
+fn absolute(message: &str) -> String {
+    let message = message.trim();
+    if message.starts_with(':') || message.starts_with('*') {
+        message.to_string()
+    } else {
+        format!(":{}", message)
+    }
+}
+
+// Splits a compound response on the `;` that are not inside a quoted string.
+pub fn split_replies(response: &str) -> Vec<String> {
+    let mut replies = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in response.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                current.push(c);
+            }
+            ';' if !quoted => replies.push(std::mem::take(&mut current)),
+            c => current.push(c),
+        }
+    }
+    replies.push(current);
+    replies.into_iter().map(|reply| reply.trim().to_string()).collect()
+}
+
+impl<'a, V: Visa> Batch<'a, V> {
+    pub(crate) fn new(scope: &'a mut Ds1000z<V>) -> Batch<'a, V> {
+        Batch { scope, messages: Vec::new(), queries: 0 }
+    }
+
+    pub fn command(&mut self, command: &str) -> &mut Batch<'a, V> {
+        self.messages.push(absolute(command));
+        self
+    }
+
+    pub fn query<T>(&mut self, query: &str) -> Reply<T> {
+        self.messages.push(absolute(query));
+        self.queries += 1;
+        Reply { index: self.queries - 1, marker: PhantomData }
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    // A batch of queries only is sent again after a resync like any other query. One
+    // with commands in it is not: the commands would run twice.
+    pub fn send(self) -> Result<Replies> {
+        let quirks = self.scope.quirks().to_vec();
+        if self.messages.is_empty() {
+            return Ok(Replies { replies: Vec::new(), quirks });
+        }
+        let message = self.messages.join(";");
+        let commands = self.messages.len() - self.queries;
+        self.scope.cache.invalidate(&message);
+        if self.queries == 0 {
+            self.scope.send(&message)?;
+            self.scope.written(&message, commands)?;
+            return Ok(Replies { replies: Vec::new(), quirks });
+        }
+        let response = if commands == 0 {
+            self.scope.query(&message)?
+        } else {
+            self.scope.send(&message)?;
+            let response = self.scope.device().read_result()?;
+            self.scope.written(&message, commands)?;
+            response
+        };
+        let replies = split_replies(&response);
+        if replies.len() != self.queries {
+            return Err(Error::ProtocolError(format!("{} replies to {} queries in {:?}: {:?}", replies.len(), self.queries, message, response)));
+        }
+        Ok(Replies { replies, quirks })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::WAVeformCommand::Format;
+    use crate::simulator::Simulator;
+
+    #[test]
+    fn test_split_replies() {
+        assert_eq!(split_replies("1;2.5e-3; BYTE\n"), ["1", "2.5e-3", "BYTE"]);
+        assert_eq!(split_replies("-113,\"Undefined; header\";0"), ["-113,\"Undefined; header\"", "0"]);
+    }
+
+    #[test]
+    fn test_batch() {
+        let mut scope = Ds1000z::new(Simulator::new()).unwrap();
+        let mut batch = scope.batch();
+        batch.command("WAVeform:FORMat WORD").command(":WAVeform:STARt 5");
+        let format = batch.query::<Format>(":WAVeform:FORMat?");
+        let start = batch.query::<u32>(":WAVeform:STARt?");
+        let scale = batch.query::<f32>(":TIMebase:SCALe?");
+        let replies = batch.send().unwrap();
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies.get(format).unwrap(), Format::WORD);
+        assert_eq!(replies.get(start).unwrap(), 5);
+        assert_eq!(replies.get(scale).unwrap(), 1e-3);
+        assert!(matches!(replies.get(Reply::<u32> { index: 0, marker: PhantomData }), Err(Error::ParseError { .. })));
+        assert!(matches!(replies.get(Reply::<u32> { index: 3, marker: PhantomData }), Err(Error::InvalidArgument(_))));
+
+        // a query the scope does not answer leaves the reply count short
+        scope.set_recovery(crate::Recovery::Off);
+        let mut batch = scope.batch();
+        batch.query::<u32>(":WAVeform:STARt?");
+        batch.query::<u32>(":WAVeform:BOGus?");
+        assert!(matches!(batch.send(), Err(Error::ProtocolError(_))));
+    }
+
+    #[test]
+    fn test_not_available_reply() {
+        // 9.9E37 is what the scope answers for a measurement it can not make
+        let replies = Replies { replies: vec!["9.9E37".to_string(), "1.0e-3".to_string()], quirks: Vec::new() };
+        let reply = |index| Reply::<f32> { index, marker: PhantomData };
+        assert!(matches!(replies.get(reply(0)), Err(Error::NotAvailable)));
+        assert_eq!(replies.get(reply(1)).unwrap(), 1e-3);
+        assert!(matches!(replies.get(Reply::<f64> { index: 0, marker: PhantomData }), Err(Error::NotAvailable)));
+        assert!(matches!(replies.get(Reply::<u32> { index: 0, marker: PhantomData }), Err(Error::ParseError { .. })));
+    }
+}
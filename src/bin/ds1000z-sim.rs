@@ -0,0 +1,41 @@
+use std::net::TcpListener;
+use std::process;
+use std::sync::{Arc, Mutex};
+use ds1000z::simulator::{self, Instrument};
+
+const USAGE: &str = "usage: ds1000z-sim [--listen ADDRESS] [--idn IDENTIFICATION]
+
+Emulates the raw SCPI socket of a RIGOL DS1000Z oscilloscope.
+  --listen ADDRESS   address to accept connections on (default 127.0.0.1:5555;
+                     0.0.0.0:5555 accepts them from other hosts too)
+  --idn TEXT         *IDN? response, e.g. \"RIGOL TECHNOLOGIES,DS1102Z-E,DS1ZE000000001,00.06.02\"";
+
+fn main() {
+    let mut address = "127.0.0.1:5555".to_string();
+    let mut instrument = Instrument::default();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--listen", Some(value)) => address = value,
+            ("--idn", Some(value)) => instrument.idn = value,
+            _ => {
+                eprintln!("{}", USAGE);
+                process::exit(2);
+            }
+        }
+    }
+
+    let listener = match TcpListener::bind(&address) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("can not listen on {}: {}", address, err);
+            process::exit(1);
+        }
+    };
+    eprintln!("simulating {} on {}", instrument.idn, address);
+    let closed = |peer, err| eprintln!("connection {:?} closed: {}", peer, err);
+    if let Err(err) = simulator::serve_with(listener, Arc::new(Mutex::new(instrument)), closed) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
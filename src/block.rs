@@ -0,0 +1,340 @@
+use std::fmt;
+use std::io::{self, BufRead};
+
+// IEEE 488.2 arbitrary block data: `#<n><len><payload>` where `<n>` is the number of
+// length digits, followed by the response terminator '\n'. `#0<payload>\n` is the
+// indefinite form, terminated only by the final '\n'.
+
+#[derive(Debug)]
+pub enum BlockError {
+    IoError(io::Error),
+    InvalidHeader(Vec<u8>),
+    Truncated { expected: usize, received: usize },
+    MissingTerminator(Option<u8>),
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockError::IoError(err) => write!(f, "IO error while reading block: {}", err),
+            BlockError::InvalidHeader(header) => write!(f, "invalid block header {:?}", String::from_utf8_lossy(header)),
+            BlockError::Truncated { expected, received } => write!(f, "block truncated: expected {} bytes, received {}", expected, received),
+            BlockError::MissingTerminator(Some(byte)) => write!(f, "block not terminated by '\\n', found 0x{:02x}", byte),
+            BlockError::MissingTerminator(None) => write!(f, "block not terminated by '\\n', stream ended"),
+        }
+    }
+}
+
+impl std::error::Error for BlockError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BlockError::IoError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BlockError {
+    fn from(err: io::Error) -> Self {
+        BlockError::IoError(err)
+    }
+}
+
+// The largest block a DS1000Z sends: 24 Mpts in WORD format, plus slack. A header
+// claiming more is corrupt or out of step, and is refused before anything is allocated.
+pub const MAX_BLOCK_LENGTH: usize = 24_000_000 * 2 + 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BlockLength {
+    Definite(usize),
+    Indefinite,
+}
+
+// Parses the block header at the start of `buf`. Returns `Ok(None)` while more bytes are
+// needed, otherwise the payload length and the size of the header in bytes.
+pub fn parse_header(buf: &[u8]) -> Result<Option<(BlockLength, usize)>, BlockError> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    if buf[0] != b'#' {
+        return Err(BlockError::InvalidHeader(buf[..buf.len().min(12)].to_vec()));
+    }
+    let Some(&digit) = buf.get(1) else {
+        return Ok(None);
+    };
+    let digits = match (digit as char).to_digit(10) {
+        Some(d) => d as usize,
+        None => return Err(BlockError::InvalidHeader(buf[..2].to_vec())),
+    };
+    if digits == 0 {
+        return Ok(Some((BlockLength::Indefinite, 2)));
+    }
+    if buf.len() < 2 + digits {
+        return Ok(None);
+    }
+    let field = &buf[2..2 + digits];
+    if !field.iter().all(u8::is_ascii_digit) {
+        return Err(BlockError::InvalidHeader(buf[..2 + digits].to_vec()));
+    }
+    // at most nine digits, so this never overflows
+    let length = field.iter().fold(0usize, |acc, d| acc * 10 + (d - b'0') as usize);
+    if length > MAX_BLOCK_LENGTH {
+        return Err(BlockError::InvalidHeader(buf[..2 + digits].to_vec()));
+    }
+    Ok(Some((BlockLength::Definite(length), 2 + digits)))
+}
+
+// Decodes a complete block held in memory, returning the payload and the total number of
+// bytes used including the terminator.
+pub fn decode_block(buf: &[u8]) -> Result<(&[u8], usize), BlockError> {
+    let (length, header_len) = match parse_header(buf)? {
+        Some(header) => header,
+        None => return Err(BlockError::InvalidHeader(buf.to_vec())),
+    };
+    let body = &buf[header_len..];
+    match length {
+        BlockLength::Definite(length) => {
+            if body.len() < length {
+                return Err(BlockError::Truncated { expected: length, received: body.len() });
+            }
+            match body.get(length) {
+                Some(b'\n') => Ok((&body[..length], header_len + length + 1)),
+                other => Err(BlockError::MissingTerminator(other.copied())),
+            }
+        }
+        BlockLength::Indefinite => match body.last() {
+            Some(b'\n') => Ok((&body[..body.len() - 1], buf.len())),
+            other => Err(BlockError::MissingTerminator(other.copied())),
+        },
+    }
+}
+
+// Extracts the block from a complete message of a transport with END signalling
+// (VXI-11, USBTMC). END delimits the message, so the '\n' after a definite length block
+// is optional.
+pub fn from_message(mut message: Vec<u8>) -> Result<Vec<u8>, BlockError> {
+    match parse_header(&message)? {
+        Some((BlockLength::Definite(length), header)) => {
+            let received = message.len() - header;
+            if received < length {
+                return Err(BlockError::Truncated { expected: length, received });
+            }
+            message.truncate(header + length);
+            Ok(message.split_off(header))
+        }
+        Some((BlockLength::Indefinite, header)) => {
+            if message.last() == Some(&b'\n') {
+                message.pop();
+            }
+            Ok(message.split_off(header))
+        }
+        None => Err(BlockError::InvalidHeader(message)),
+    }
+}
+
+// One block decoded from however the transport hands out its bytes: `feed` takes what
+// has arrived and says how much of it belongs to the block, and `end_of_stream` is the
+// error for a stream that ends before `is_done`. `read_block` and its async counterpart
+// only do the I/O around it.
+#[derive(Debug, Clone, Default)]
+pub struct BlockDecoder {
+    header: Vec<u8>,
+    length: Option<BlockLength>,
+    payload: Vec<u8>,
+    done: bool,
+}
+
+impl BlockDecoder {
+    pub fn new() -> BlockDecoder {
+        BlockDecoder::default()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    // Consumes the block's bytes from the start of `input` and returns their number;
+    // anything after the terminator is left alone.
+    pub fn feed(&mut self, input: &[u8]) -> Result<usize, BlockError> {
+        let mut used = 0;
+        while used < input.len() && !self.done {
+            let rest = &input[used..];
+            used += match self.length {
+                None => {
+                    // a header is at most `#9` and nine digits
+                    let wanted = match self.header.get(1) {
+                        Some(digit) if digit.is_ascii_digit() => 2 + (digit - b'0') as usize,
+                        _ => 2,
+                    };
+                    let n = (wanted - self.header.len()).min(rest.len());
+                    self.header.extend_from_slice(&rest[..n]);
+                    if let Some((length, _)) = parse_header(&self.header)? {
+                        if let BlockLength::Definite(length) = length {
+                            // grown as the data arrives, not up front
+                            self.payload.reserve(length.min(1 << 20));
+                        }
+                        self.length = Some(length);
+                    }
+                    n
+                }
+                Some(BlockLength::Definite(length)) if self.payload.len() < length => {
+                    let n = (length - self.payload.len()).min(rest.len());
+                    self.payload.extend_from_slice(&rest[..n]);
+                    n
+                }
+                Some(BlockLength::Definite(_)) => {
+                    if rest[0] != b'\n' {
+                        return Err(BlockError::MissingTerminator(Some(rest[0])));
+                    }
+                    self.done = true;
+                    1
+                }
+                // Without an END signal the best a byte stream can do is to stop once a
+                // delivered chunk ends in '\n'; message based transports always deliver
+                // the block as one unit.
+                Some(BlockLength::Indefinite) => {
+                    if self.payload.len() + rest.len() > MAX_BLOCK_LENGTH + 1 {
+                        // nor does an indefinite block get to grow past it
+                        return Err(BlockError::InvalidHeader(b"#0".to_vec()));
+                    }
+                    self.payload.extend_from_slice(rest);
+                    if self.payload.last() == Some(&b'\n') {
+                        self.payload.pop();
+                        self.done = true;
+                    }
+                    rest.len()
+                }
+            };
+        }
+        Ok(used)
+    }
+
+    // What it means that the stream ended here.
+    pub fn end_of_stream(&self) -> BlockError {
+        match self.length {
+            None => match self.header.get(1) {
+                Some(digits) => BlockError::Truncated { expected: (digits - b'0') as usize, received: self.header.len() - 2 },
+                None => BlockError::Truncated { expected: 2, received: self.header.len() },
+            },
+            Some(BlockLength::Definite(length)) if self.payload.len() < length => {
+                BlockError::Truncated { expected: length, received: self.payload.len() }
+            }
+            Some(_) => BlockError::MissingTerminator(None),
+        }
+    }
+
+    pub fn into_payload(self) -> Vec<u8> {
+        self.payload
+    }
+}
+
+// Reads one block from the stream, returning exactly the payload. The terminator is
+// consumed; anything after it is left in the reader.
+pub fn read_block<R: BufRead>(reader: &mut R) -> Result<Vec<u8>, BlockError> {
+    let mut decoder = BlockDecoder::new();
+    while !decoder.is_done() {
+        let available = match reader.fill_buf() {
+            Ok(available) => available,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+        if available.is_empty() {
+            return Err(decoder.end_of_stream());
+        }
+        let used = decoder.feed(available)?;
+        reader.consume(used);
+    }
+    Ok(decoder.into_payload())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufReader, Cursor, Read};
+
+    // Hands out at most `chunk` bytes per read, like a socket under load.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        chunk: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.chunk.min(buf.len()).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_definite_block() {
+        let mut reader = Cursor::new(b"#9000000005ab\ncd\n1.0\n".to_vec());
+        assert_eq!(read_block(&mut reader).unwrap(), b"ab\ncd");
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "1.0\n");
+    }
+
+    #[test]
+    fn test_definite_block_short_reads() {
+        let mut data = b"#3250".to_vec();
+        data.extend((0..250).map(|i| i as u8));
+        data.push(b'\n');
+        let mut reader = BufReader::with_capacity(7, Trickle { data: &data, chunk: 3 });
+        let payload = read_block(&mut reader).unwrap();
+        assert_eq!(payload.len(), 250);
+        assert_eq!(payload[249], 249);
+    }
+
+    #[test]
+    fn test_empty_and_indefinite_block() {
+        assert!(read_block(&mut Cursor::new(b"#10\n".to_vec())).unwrap().is_empty());
+        assert_eq!(read_block(&mut Cursor::new(b"#0\x01\x02\n".to_vec())).unwrap(), [1, 2]);
+    }
+
+    #[test]
+    fn test_truncated_block() {
+        match read_block(&mut Cursor::new(b"#210abc".to_vec())) {
+            Err(BlockError::Truncated { expected: 10, received: 3 }) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        match read_block(&mut Cursor::new(b"#9000".to_vec())) {
+            Err(BlockError::Truncated { expected: 9, received: 3 }) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        match read_block(&mut Cursor::new(b"#13abc".to_vec())) {
+            Err(BlockError::MissingTerminator(None)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_invalid_header() {
+        assert!(matches!(read_block(&mut Cursor::new(b"1.0\n".to_vec())), Err(BlockError::InvalidHeader(_))));
+        assert!(matches!(read_block(&mut Cursor::new(b"#x12\n".to_vec())), Err(BlockError::InvalidHeader(_))));
+        assert!(matches!(read_block(&mut Cursor::new(b"#21a\n".to_vec())), Err(BlockError::InvalidHeader(_))));
+        // refused on the header alone, before the payload is allocated
+        assert!(matches!(read_block(&mut Cursor::new(b"#9999999999".to_vec())), Err(BlockError::InvalidHeader(_))));
+        assert!(matches!(parse_header(b"#848001025"), Err(BlockError::InvalidHeader(_))));
+        assert!(matches!(parse_header(b"#848001024"), Ok(Some((BlockLength::Definite(48_001_024), 10)))));
+    }
+
+    #[test]
+    fn test_decode_block() {
+        assert_eq!(decode_block(b"#15hello\nrest").unwrap(), (&b"hello"[..], 9));
+        assert_eq!(decode_block(b"#0hi\n").unwrap(), (&b"hi"[..], 5));
+        assert!(matches!(decode_block(b"#15hell"), Err(BlockError::Truncated { expected: 5, received: 4 })));
+        assert!(matches!(decode_block(b"#15hello!"), Err(BlockError::MissingTerminator(Some(b'!')))));
+        assert_eq!(parse_header(b"#9").unwrap(), None);
+    }
+
+    #[test]
+    fn test_from_message() {
+        assert_eq!(from_message(b"#15hello".to_vec()).unwrap(), b"hello");
+        assert_eq!(from_message(b"#15hello\n".to_vec()).unwrap(), b"hello");
+        assert_eq!(from_message(b"#0hi\n".to_vec()).unwrap(), b"hi");
+        assert!(matches!(from_message(b"#15hel".to_vec()), Err(BlockError::Truncated { expected: 5, received: 3 })));
+        assert!(matches!(from_message(Vec::new()), Err(BlockError::InvalidHeader(_))));
+    }
+}
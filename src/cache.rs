@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use crate::batch::split_replies;
+use crate::scpi;
+
+// The last known value of each setting read through a `Property`, keyed by header.
+//
+// Every command written through the session drops the values it may have changed: the
+// setting itself and whatever `INVALIDATIONS` lists for it, so that changing the
+// timebase also forgets `:WAVeform:XINCrement`. Changes made on the front panel or over
+// another connection can not be seen; `Ds1000z::refresh_all` reads everything again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SettingsCache {
+    enabled: bool,
+    values: HashMap<String, String>,
+}
+
+// Everything below the root.
+const EVERYTHING: &[&str] = &[""];
+
+const X_SCALING: &[&str] = &[
+    ":WAVeform:XINCrement", ":WAVeform:XORigin", ":WAVeform:PREamble", ":WAVeform:POINts", ":ACQuire:SRATe",
+];
+
+const Y_SCALING: &[&str] = &[
+    ":WAVeform:YINCrement", ":WAVeform:YORigin", ":WAVeform:YREFerence", ":WAVeform:PREamble",
+];
+
+// The headers a command invalidates besides its own. Headers match on their leading
+// nodes in short or long form; a node without a numeric suffix stands for all of them.
+const INVALIDATIONS: &[(&str, &[&str])] = &[
+    ("*RST", EVERYTHING),
+    ("*RCL", EVERYTHING),
+    (":AUToscale", EVERYTHING),
+    (":SYSTem:SETup", EVERYTHING),
+    (":LOAD", EVERYTHING),
+    (":TIMebase", X_SCALING),
+    (":ACQuire", &[
+        ":WAVeform:XINCrement", ":WAVeform:XORigin", ":WAVeform:PREamble", ":WAVeform:POINts", ":ACQuire:SRATe",
+        ":WAVeform:STARt", ":WAVeform:STOP",
+    ]),
+    (":CHANnel", Y_SCALING),
+    (":WAVeform:SOURce", Y_SCALING),
+    (":WAVeform:MODE", &[
+        ":WAVeform:XINCrement", ":WAVeform:XORigin", ":WAVeform:PREamble", ":WAVeform:POINts",
+        ":WAVeform:STARt", ":WAVeform:STOP",
+    ]),
+    (":WAVeform:FORMat", &[":WAVeform:PREamble"]),
+    (":WAVeform:STARt", &[":WAVeform:PREamble", ":WAVeform:POINts"]),
+    (":WAVeform:STOP", &[":WAVeform:PREamble", ":WAVeform:POINts"]),
+    (":RUN", &[":WAVeform:PREamble", ":WAVeform:POINts"]),
+    (":STOP", &[":WAVeform:PREamble", ":WAVeform:POINts"]),
+    (":SINGle", &[":TRIGger:SWEep", ":WAVeform:PREamble", ":WAVeform:POINts"]),
+    (":TRIGger:SWEep", &[":WAVeform:PREamble", ":WAVeform:POINts"]),
+];
+
+fn nodes(header: &str) -> Vec<&str> {
+    let header = header.split_whitespace().next().unwrap_or("").trim_end_matches('?');
+    header.split(':').filter(|node| !node.is_empty()).collect()
+}
+
+// Either side may be in SCPI case (`CHANnel1`) or as written by the user (`chan1`).
+fn same_node(a: &str, b: &str) -> bool {
+    scpi::matches(a, b) || scpi::matches(b, a)
+}
+
+fn node_matches(pattern: &str, node: &str) -> bool {
+    if same_node(pattern, node) {
+        return true;
+    }
+    let stem = node.trim_end_matches(|c: char| c.is_ascii_digit());
+    stem.len() < node.len() && !pattern.ends_with(|c: char| c.is_ascii_digit()) && same_node(pattern, stem)
+}
+
+// Whether `header` is `pattern` or lies below it.
+fn header_matches(pattern: &str, header: &str) -> bool {
+    let pattern = nodes(pattern);
+    let header = nodes(header);
+    pattern.len() <= header.len() && pattern.iter().zip(&header).all(|(pattern, node)| node_matches(pattern, node))
+}
+
+impl SettingsCache {
+    pub fn new() -> SettingsCache {
+        SettingsCache { enabled: true, values: HashMap::new() }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    // A disabled cache keeps nothing, so every value comes from the scope.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.values.clear();
+    }
+
+    pub fn get(&self, header: &str) -> Option<&str> {
+        self.values.get(header).map(String::as_str)
+    }
+
+    pub fn headers(&self) -> impl Iterator<Item = &str> {
+        self.values.keys().map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub(crate) fn store(&mut self, header: &str, response: &str) {
+        if self.enabled {
+            self.values.insert(header.to_string(), response.trim().to_string());
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+
+    // Drops what the commands in a (compound) program message may have changed.
+    pub fn invalidate(&mut self, message: &str) {
+        for unit in split_replies(message) {
+            let header = unit.split_whitespace().next().unwrap_or("");
+            if header.is_empty() || header.ends_with('?') {
+                continue;
+            }
+            let affected: Vec<&str> = INVALIDATIONS.iter()
+                .filter(|(command, _)| header_matches(command, header))
+                .flat_map(|(_, affected)| affected.iter().copied())
+                .collect();
+            self.values.retain(|cached, _| !header_matches(header, cached) && !affected.iter().any(|pattern| header_matches(pattern, cached)));
+        }
+    }
+}
+
+impl Default for SettingsCache {
+    fn default() -> Self {
+        SettingsCache::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(headers: &[&str]) -> SettingsCache {
+        let mut cache = SettingsCache::new();
+        for header in headers {
+            cache.store(header, "1");
+        }
+        cache
+    }
+
+    fn remaining(cache: &SettingsCache) -> Vec<&str> {
+        let mut headers: Vec<&str> = cache.headers().collect();
+        headers.sort();
+        headers
+    }
+
+    #[test]
+    fn test_invalidation_rules() {
+        let headers = [":TIMebase:SCALe", ":WAVeform:XINCrement", ":WAVeform:YINCrement", ":CHANnel2:SCALe", ":TRIGger:SWEep"];
+        let mut settings = cache(&headers);
+        settings.invalidate(":tim:scal 2e-3");
+        assert_eq!(remaining(&settings), [":CHANnel2:SCALe", ":TRIGger:SWEep", ":WAVeform:YINCrement"]);
+
+        let mut settings = cache(&headers);
+        settings.invalidate(":CHAN1:OFFS 0.5;:WAV:SOUR CHAN2");
+        assert_eq!(remaining(&settings), [":CHANnel2:SCALe", ":TIMebase:SCALe", ":TRIGger:SWEep", ":WAVeform:XINCrement"]);
+
+        // a channel's own settings are only dropped by commands to that channel
+        let mut settings = cache(&headers);
+        settings.invalidate(":CHANnel2:SCALe 1");
+        assert_eq!(remaining(&settings), [":TIMebase:SCALe", ":TRIGger:SWEep", ":WAVeform:XINCrement"]);
+
+        let mut settings = cache(&headers);
+        settings.invalidate(":WAVeform:XINCrement?;:TRIGger:STATus?");
+        assert_eq!(settings.len(), headers.len());
+        settings.invalidate("*RST");
+        assert!(settings.is_empty());
+
+        settings.set_enabled(false);
+        settings.store(":TIMebase:SCALe", "1");
+        assert_eq!(settings.get(":TIMebase:SCALe"), None);
+    }
+}
@@ -0,0 +1,67 @@
+use crate::device::Visa;
+use crate::error::Result;
+use crate::property::Property;
+use crate::session::Ds1000z;
+
+// Indexed by channel number - 1.
+pub const DISPLAY: [Property<bool>; 4] = [
+    Property::new(":CHANnel1:DISPlay"),
+    Property::new(":CHANnel2:DISPlay"),
+    Property::new(":CHANnel3:DISPlay"),
+    Property::new(":CHANnel4:DISPlay"),
+];
+pub const SCALE: [Property<f32>; 4] = [
+    Property::new(":CHANnel1:SCALe"),
+    Property::new(":CHANnel2:SCALe"),
+    Property::new(":CHANnel3:SCALe"),
+    Property::new(":CHANnel4:SCALe"),
+];
+pub const OFFSET: [Property<f32>; 4] = [
+    Property::new(":CHANnel1:OFFSet"),
+    Property::new(":CHANnel2:OFFSet"),
+    Property::new(":CHANnel3:OFFSet"),
+    Property::new(":CHANnel4:OFFSet"),
+];
+
+pub struct CHANnelCommand<'a, V: Visa> {
+    scope: &'a mut Ds1000z<V>,
+    channel: u8,
+}
+
+impl<'a, V: Visa> CHANnelCommand<'a, V> {
+    pub(crate) fn new(scope: &'a mut Ds1000z<V>, channel: u8) -> CHANnelCommand<'a, V> {
+        CHANnelCommand { scope, channel }
+    }
+
+    pub fn channel(&self) -> u8 {
+        self.channel
+    }
+
+    pub fn set_display(&mut self, display: bool) -> Result<()> {
+        DISPLAY[self.index()].set(self.scope, display)
+    }
+
+    pub fn get_display(&mut self) -> Result<bool> {
+        DISPLAY[self.index()].get(self.scope)
+    }
+
+    pub fn set_scale(&mut self, scale: f32) -> Result<()> {
+        SCALE[self.index()].set(self.scope, scale)
+    }
+
+    pub fn get_scale(&mut self) -> Result<f32> {
+        SCALE[self.index()].get(self.scope)
+    }
+
+    pub fn set_offset(&mut self, offset: f32) -> Result<()> {
+        OFFSET[self.index()].set(self.scope, offset)
+    }
+
+    pub fn get_offset(&mut self) -> Result<f32> {
+        OFFSET[self.index()].get(self.scope)
+    }
+
+    fn index(&self) -> usize {
+        self.channel as usize - 1
+    }
+}
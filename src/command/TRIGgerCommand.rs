@@ -1,87 +1,54 @@
-use std::str::FromStr;
-use std::fmt;
 use crate::device::Visa;
-use std::io;
-pub type Result<T, E = Error> = std::result::Result<T, E>;
-
-#[derive(Debug)]
-pub enum Error {
-    IoError(std::io::Error),
-}
-
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Error::IoError(err) => write!(f, "IO error: {}", err),
-        }
+use crate::error::Result;
+use crate::property::Property;
+use crate::scpi::scpi_enum;
+use crate::session::Ds1000z;
+scpi_enum! {
+    pub enum SWEep {
+        AUTO => "AUTO",
+        NORM => "NORMal",
+        SING => "SINGle",
     }
 }
 
-impl std::error::Error for Error {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        match self {
-            Error::IoError(err) => Some(err),
-        }
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TRIGgerState {
+    pub sweep: SWEep,
 }
 
-impl From<std::io::Error> for Error {
-    fn from(err: std::io::Error) -> Self {
-        Error::IoError(err)
+impl Default for TRIGgerState {
+    fn default() -> Self {
+        TRIGgerState { sweep: SWEep::AUTO }
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub enum SWEep{
-    AUTO,
-    NORM,
-    SING,
-}
+pub const SWEEP: Property<SWEep> = Property::new(":TRIGger:SWEep");
 
-impl fmt::Display for SWEep {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", match self {
-            SWEep::AUTO => "AUTO",
-            SWEep::NORM => "NORM",
-            SWEep::SING => "SING",
-        })
-    }
+pub struct TRIGgerCommand<'a, V: Visa>{
+    scope: &'a mut Ds1000z<V>,
 }
 
-impl FromStr for SWEep {
-    type Err = Error;
+impl<'a, V: Visa> TRIGgerCommand<'a, V> {
+    pub(crate) fn new(scope: &'a mut Ds1000z<V>) -> TRIGgerCommand<'a, V> {
+        TRIGgerCommand { scope }
+    }
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "AUTO" => Ok(SWEep::AUTO),
-            "NORM" => Ok(SWEep::NORM),
-            "SING" => Ok(SWEep::SING),
-            _ => Err(Error::IoError(io::Error::new(io::ErrorKind::InvalidData, "Invalid SWEep"))),
-        }
+    pub fn state(&self) -> &TRIGgerState {
+        &self.scope.trigger_state
     }
-}
 
-#[derive(Debug)]
-pub struct TRIGgerCommand{
-    pub device: std::net::TcpStream,
-    pub sweep: SWEep,
-}
+    pub fn set_sweep(&mut self, sweep: SWEep) -> Result<()> {
+        SWEEP.set(self.scope, sweep)
+    }
 
-impl TRIGgerCommand {
     pub fn get_sweep(&mut self) -> Result<SWEep>{
-        self.device.write_scip_cmd(b":TRIGger:SWEep?\n")?;
-        let buffer :String = self.device.read_result(1)?;
-        let swp = buffer.trim_end_matches('\n');
-        println!("swp = {}", swp);
-        return swp.parse()
+        let swp = SWEEP.get(self.scope)?;
+        self.scope.trigger_state.sweep = swp;
+        Ok(swp)
     }
 
-    pub fn new(device: std::net::TcpStream) -> Result<TRIGgerCommand> {
-        let mut cmd = TRIGgerCommand {
-            device: device,
-            sweep: SWEep::AUTO,
-        };
-        cmd.sweep = cmd.get_sweep()?;
-        Ok(cmd)
+    pub fn sweep(&mut self, sweep: SWEep) -> Result<SWEep> {
+        self.set_sweep(sweep)?;
+        self.get_sweep()
     }
 }
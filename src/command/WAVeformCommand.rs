@@ -1,83 +1,18 @@
 use std::fmt;
-use std::io::{self};
-use crate::device::Visa;
-use crate::command::TRIGgerCommand;
+use crate::batch::Reply;
+use crate::command::CHANnelCommand;
+use crate::device::{store_bytes_u16, store_bytes_u8, Visa};
+use crate::error::{Error, Result};
+use crate::firmware::{self, Quirk};
+use crate::command::TRIGgerCommand::{SWEep, SWEEP};
+use crate::scpi::scpi_enum;
+use crate::number::parse_number;
+use crate::property::{Property, PropertyValue};
+use crate::session::Ds1000z;
+use std::ops::{Deref, DerefMut};
 use std::str::FromStr;
 
-pub type Result<T, E = Error> = std::result::Result<T, E>;
-
-#[derive(Debug)]
-pub enum Error {
-    IoError(std::io::Error),
-    CanNotChangeMode(Mode),
-    ParseFloatError(std::num::ParseFloatError),
-    ParseIntError(std::num::ParseIntError),
-    ExceededMaxMemorySize(MaxMemorySize),
-    StartIsGreaterThanStop(u32, u32),
-    ExceedeMaxTransferSize(MaxTransferSize),
-    CustomError(String),
-}
-
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Error::IoError(err) => write!(f, "IO error: {}", err),
-            Error::CanNotChangeMode(err) => write!(f, "The mode MAX and RAW has to set the triger mode to SINGLE: {}", err),
-            Error::ParseFloatError(err) => write!(f, "Can not convert from the string is from result of scip cmmand {} to f32", err),
-            Error::ParseIntError(err) => write!(f, "Can not convert from the string is from result of scip cmmand {} to u32", err),
-            Error::ExceededMaxMemorySize(memory_size) => write!(f, "Exceeded the max memory size {:?}", memory_size),
-            Error::StartIsGreaterThanStop(start, stop) => write!(f, "the start point {} is greater than the stop point {}", start, stop),
-            Error::ExceedeMaxTransferSize(transfer_size) => write!(f, "Exceeded the max transfer size {:?}", transfer_size),
-            Error::CustomError(e) => write!(f, "Custom error: {}", e),
-        }
-    }
-}
-
-impl std::error::Error for Error {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        match self {
-            Error::IoError(err) => Some(err),
-            Error::CanNotChangeMode(_) => None,
-            Error::ParseFloatError(err) => Some(err),
-            Error::ParseIntError(err) => Some(err),
-            Error::ExceededMaxMemorySize(_) => None, 
-            Error::StartIsGreaterThanStop(_, _) => None,
-            Error::ExceedeMaxTransferSize(_) => None,
-            Error::CustomError(_) => None,
-        }
-    }
-}
-
-impl From<std::num::ParseFloatError> for Error {
-    fn from(err: std::num::ParseFloatError) -> Self {
-        Error::ParseFloatError(err)
-    }
-}
-
-impl From<std::num::ParseIntError> for Error {
-    fn from(err: std::num::ParseIntError) -> Self {
-        Error::ParseIntError(err)
-    }
-}
-
-impl From<std::io::Error> for Error {
-    fn from(err: std::io::Error) -> Self {
-        Error::IoError(err)
-    }
-}
-
-impl From<Mode> for Error {
-    fn from(err: Mode) -> Self {
-        Error::CanNotChangeMode(err)
-    }
-}
-
-impl From<Box<dyn std::error::Error>> for Error {
-    fn from(err: Box<dyn std::error::Error>) -> Self {
-        Error::CustomError(format!("Internal error: {}", err))
-    }
-}
-
+#[allow(non_camel_case_types)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum MemoryDepth {
     DS1102Z_E = 24000000, // 24Mpts
@@ -92,159 +27,95 @@ impl fmt::Display for MemoryDepth {
 }
 
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub enum Source {
-    D0, D1, D2, D3, D4, D5, D6, D7, D8, D9, D10, D11, D12, D13, D14, D15,
-    CHAN1, CHAN2, CHAN3, CHAN4, MATH,
-}
-
-impl FromStr for Source {
-    type Err = Error;
-
-    fn from_str(s: &str) -> Result<Self> {
-        match s {
-            "D0" => Ok(Source::D0),
-            "D1" => Ok(Source::D1),
-            "D2" => Ok(Source::D2),
-            "D3" => Ok(Source::D3),
-            "D4" => Ok(Source::D4),
-            "D5" => Ok(Source::D5),
-            "D6" => Ok(Source::D6),
-            "D7" => Ok(Source::D7),
-            "D8" => Ok(Source::D8),
-            "D9" => Ok(Source::D9),
-            "D10" => Ok(Source::D10),
-            "D11" => Ok(Source::D11),
-            "D12" => Ok(Source::D12),
-            "D13" => Ok(Source::D13),
-            "D14" => Ok(Source::D14),
-            "D15" => Ok(Source::D15),
-            "CHAN1" => Ok(Source::CHAN1),
-            "CHAN2" => Ok(Source::CHAN2),
-            "CHAN3" => Ok(Source::CHAN3),
-            "CHAN4" => Ok(Source::CHAN4),
-            "MATH" => Ok(Source::MATH),
-            _ => Err(Error::IoError(io::Error::new(io::ErrorKind::InvalidData, "Invalid source"))),
-        }
-    }
-}
-
-impl fmt::Display for Source {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", match self {
-            Source::D0 => "D0",
-            Source::D1 => "D1",
-            Source::D2 => "D2",
-            Source::D3 => "D3",
-            Source::D4 => "D4",
-            Source::D5 => "D5",
-            Source::D6 => "D6",
-            Source::D7 => "D7",
-            Source::D8 => "D8",
-            Source::D9 => "D9",
-            Source::D10 => "D10",
-            Source::D11 => "D11",
-            Source::D12 => "D12",
-            Source::D13 => "D13",
-            Source::D14 => "D14",
-            Source::D15 => "D15",
-            Source::CHAN1 => "CHAN1",
-            Source::CHAN2 => "CHAN2",
-            Source::CHAN3 => "CHAN3",
-            Source::CHAN4 => "CHAN4",
-            Source::MATH => "MATH",
-        })
-    }
-}
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub enum Mode {
-    NORM, MAX, RAW,
-}
-
-impl FromStr for Mode {
-    type Err = Error;
-
-    fn from_str(s: &str) -> Result<Self> {
-        match s {
-            "NORM" => Ok(Mode::NORM),
-            "MAX" => Ok(Mode::MAX),
-            "RAW" => Ok(Mode::RAW),
-            _ => Err(Error::IoError(io::Error::new(io::ErrorKind::InvalidData, "Invalid mode"))),
+scpi_enum! {
+    pub enum Source {
+        D0 => "D0",
+        D1 => "D1",
+        D2 => "D2",
+        D3 => "D3",
+        D4 => "D4",
+        D5 => "D5",
+        D6 => "D6",
+        D7 => "D7",
+        D8 => "D8",
+        D9 => "D9",
+        D10 => "D10",
+        D11 => "D11",
+        D12 => "D12",
+        D13 => "D13",
+        D14 => "D14",
+        D15 => "D15",
+        CHAN1 => "CHANnel1",
+        CHAN2 => "CHANnel2",
+        CHAN3 => "CHANnel3",
+        CHAN4 => "CHANnel4",
+        MATH => "MATH",
+    }
+}
+
+impl Source {
+    // 1 to 4 for CHANnel1 to CHANnel4, `None` for the digital channels and MATH.
+    pub fn channel(self) -> Option<u8> {
+        match self {
+            Source::CHAN1 => Some(1),
+            Source::CHAN2 => Some(2),
+            Source::CHAN3 => Some(3),
+            Source::CHAN4 => Some(4),
+            _ => None,
         }
     }
 }
 
-impl fmt::Display for Mode {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", match self {
-            Mode::NORM => "NORM",
-            Mode::MAX => "MAX",
-            Mode::RAW => "RAW",
-        })
+scpi_enum! {
+    pub enum Mode {
+        NORM => "NORMal",
+        MAX => "MAXimum",
+        RAW => "RAW",
     }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum MaxMemorySize {
     NORM(i32),
-    MAX(MemoryDepth),
-    RAW(MemoryDepth),
+    MAX(u32),
+    RAW(u32),
 }
 
 impl fmt::Display for MaxMemorySize {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             MaxMemorySize::NORM(val) => write!(f, "NORM({})", val),
-            MaxMemorySize::MAX(depth) => write!(f, "MAX({})", depth),
-            MaxMemorySize::RAW(depth) => write!(f, "RAW({})", depth),
+            MaxMemorySize::MAX(points) => write!(f, "MAX({})", points),
+            MaxMemorySize::RAW(points) => write!(f, "RAW({})", points),
         }
     }
 }
 
 
 impl MaxMemorySize {
-    fn new(mode: Mode, memory_depth: MemoryDepth) -> MaxMemorySize {
+    // `points` is the number of points in the acquisition memory.
+    fn new(mode: Mode, points: u32) -> MaxMemorySize {
         match mode {
-            Mode::MAX => return MaxMemorySize::MAX(memory_depth),
-            Mode::RAW => return MaxMemorySize::MAX(memory_depth),
-            Mode::NORM => return MaxMemorySize::NORM(1200),
+            Mode::MAX => MaxMemorySize::MAX(points),
+            Mode::RAW => MaxMemorySize::RAW(points),
+            Mode::NORM => MaxMemorySize::NORM(1200),
         }
     }
 
-    fn to_u32(&self) -> u32 {
-        match *self {
+    fn to_u32(self) -> u32 {
+        match self {
             MaxMemorySize::NORM(val) => val as u32,
-            MaxMemorySize::MAX(depth) => depth as u32, 
-            MaxMemorySize::RAW(depth) => depth as u32, 
+            MaxMemorySize::MAX(points) => points,
+            MaxMemorySize::RAW(points) => points,
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub enum Format {
-    WORD, BYTE, ASC,
-}
-
-impl fmt::Display for Format {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", match self {
-            Format::WORD => "WORD",
-            Format::BYTE => "BYTE",
-            Format::ASC => "ASC",
-        })
-    }
-}
-
-impl FromStr for Format {
-    type Err = Error;
-    fn from_str(s: &str) -> Result<Self> {
-        match s {
-            "WORD" => Ok(Format::WORD),
-            "BYTE" => Ok(Format::BYTE),
-            "ASC" => Ok(Format::ASC),
-            _ => Err(Error::IoError(io::Error::new(io::ErrorKind::InvalidData, "Invalid mode"))),
-        }
+scpi_enum! {
+    pub enum Format {
+        WORD => "WORD",
+        BYTE => "BYTE",
+        ASC => "ASCii",
     }
 }
 
@@ -312,76 +183,138 @@ pub struct ConvertData {
     pub count: u32,
 }
 
+impl Default for ConvertData {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ConvertData {
     pub fn new() -> Self {
-        return ConvertData{ data : vec![TwoDiv{x: 0.0, y: 0.0}; MemoryDepth::DS1102Z_E as usize], count: 0};
+        ConvertData{ data : vec![TwoDiv{x: 0.0, y: 0.0}; MemoryDepth::DS1102Z_E as usize], count: 0}
     }
 
-    pub fn convert_voltage(&mut self, wavedata: &WAVeformCommands) -> Result<()> {
+    pub fn convert_voltage(&mut self, wavedata: &WAVeformState) -> Result<()> {
         match &wavedata.data{
-            RecieveData::ASC(recv) => {
+            RecieveData::ASC(_recv) => {
                 Ok(())                
             }
             RecieveData::BYTE(recv) => {
-                let mut point: u32 = 0;
-                let start = wavedata.start_point as u32;
-                let stop = wavedata.stop_point as u32;
+                let start = wavedata.start_point;
+                let stop = wavedata.stop_point;
                 let size: u32 = stop - start + 1;
-                println!("convert size = {}, {}, {}, {}", self.count, size, wavedata.origin.y, wavedata.reference.y);
+                if recv.len() != size as usize {
+                    return Err(Error::ProtocolError(format!("expected {} points for {}..={}, received {}", size, start, stop, recv.len())));
+                }
                 let data_slice = &mut self.data.get_mut(self.count as usize..(self.count + size) as usize);
                 match data_slice {
                     Some(data_some) => {
-                        for (re, cd) in recv.iter().zip(data_some.iter_mut()) {
+                        // x counts from the first point in memory, not from this chunk
+                        for (point, (re, cd)) in recv.iter().zip(data_some.iter_mut()).enumerate() {
                             cd.y = (*re as f32 - wavedata.origin.y - wavedata.reference.y) * wavedata.increment.y;
-                            cd.x = wavedata.origin.x + point as f32 * wavedata.increment.x; 
-                            point = point + 1;
-                            if point > size{
-                                break;
-                            }
+                            cd.x = wavedata.origin.x + (start - 1 + point as u32) as f32 * wavedata.increment.x;
                         }
-                        println!("sizeaaa = {}, count = {}", size, self.count);
-                        self.count = self.count + size as u32;
-                        println!("sizeaaa = {}, count = {}", size, self.count);
+                        self.count += size;
                         Ok(())
                     }
-                    None => {
-                        println!("sizeaaa = none");
-                            Ok(())
-                    }
+                    None => Err(Error::InvalidArgument(format!("{} points do not fit after the {} already converted", size, self.count))),
                 }
             }
-            RecieveData::WORD(recv) => {
-                Ok(())
-            }
-            _ => {
+            RecieveData::WORD(_recv) => {
                 Ok(())
             }
         }
     }
 }
 
-#[derive(Debug)]
-pub struct WAVeformCommands {
-    pub device: std::net::TcpStream,
-    pub memory_depth: MemoryDepth,
-    pub max_transfer_size: MaxTransferSize,
+// The ten fields of `:WAVeform:PREamble?`:
+// `<format>,<type>,<points>,<count>,<xincrement>,<xorigin>,<xreference>,<yincrement>,<yorigin>,<yreference>`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Preamble {
+    pub format: Format,
+    pub mode: Mode,
+    pub points: u32,
+    pub count: u32,
+    pub x_increment: f64,
+    pub x_origin: f64,
+    pub x_reference: f64,
+    pub y_increment: f64,
+    pub y_origin: f64,
+    pub y_reference: f64,
+}
+
+impl FromStr for Preamble {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Preamble::parse(s, &[])
+    }
+}
+
+impl Preamble {
+    // The points and count fields are integers, in NR3 on a scope with
+    // `Quirk::Nr3Integers`.
+    pub fn parse(s: &str, quirks: &[Quirk]) -> Result<Preamble> {
+        let fields: Vec<&str> = s.trim().split(',').collect();
+        if fields.len() != 10 {
+            return Err(Error::parse_error(s, "Preamble"));
+        }
+        let format = match fields[0].trim() {
+            "0" => Format::BYTE,
+            "1" => Format::WORD,
+            "2" => Format::ASC,
+            _ => return Err(Error::parse_error(s, "Preamble")),
+        };
+        let mode = match fields[1].trim() {
+            "0" => Mode::NORM,
+            "1" => Mode::MAX,
+            "2" => Mode::RAW,
+            _ => return Err(Error::parse_error(s, "Preamble")),
+        };
+        Ok(Preamble {
+            format,
+            mode,
+            points: firmware::parse_u32(fields[2], quirks)?,
+            count: firmware::parse_u32(fields[3], quirks)?,
+            x_increment: parse_number(fields[4])?,
+            x_origin: parse_number(fields[5])?,
+            x_reference: parse_number(fields[6])?,
+            y_increment: parse_number(fields[7])?,
+            y_origin: parse_number(fields[8])?,
+            y_reference: parse_number(fields[9])?,
+        })
+    }
+}
+
+// The settings (format, mode, start and stop points and the sizes that follow from
+// them) are not public: `WAVeformCommands` takes them from the settings cache
+// before each use, `AsyncWAVeformCommands` records them as it reads them.
+#[derive(Debug, Clone)]
+pub struct WAVeformState {
+    pub(crate) max_transfer_size: MaxTransferSize,
     pub data: RecieveData,
-    pub start_point: u32,
-    pub stop_point: u32,
+    pub(crate) start_point: u32,
+    pub(crate) stop_point: u32,
     pub origin: TwoDiv<f32>,
     pub reference: TwoDiv<f32>,
     pub increment: TwoDiv<f32>,
-    pub source: Source,
-    pub format: Format,
-    pub max_memory_size: MaxMemorySize,
-    pub mode: Mode,
+    pub(crate) format: Format,
+    pub(crate) max_memory_size: MaxMemorySize,
+    // the points of the last acquisition, see `WAVeformCommands::get_memory_depth`
+    pub(crate) acquisition_points: u32,
+    pub(crate) mode: Mode,
+    pub preamble: Option<Preamble>,
+}
+
+impl Default for WAVeformState {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl WAVeformCommands {
-    pub fn new(device: std::net::TcpStream, memory_depth: MemoryDepth, trigger: & TRIGgerCommand::TRIGgerCommand) -> Result<WAVeformCommands> {
-        let mut cmd = WAVeformCommands {
-            device: device,
-            memory_depth: memory_depth,
+impl WAVeformState {
+    pub fn new() -> WAVeformState {
+        WAVeformState {
             max_transfer_size: MaxTransferSize::WORD,
             data: RecieveData::ASC(Vec::new()) ,
             start_point: 0,
@@ -389,159 +322,364 @@ impl WAVeformCommands {
             origin: TwoDiv { x: 0.0, y: 0.0 },
             reference: TwoDiv { x: 0.0, y: 0.0 },
             increment: TwoDiv { x: 0.0, y: 0.0 },
-            source: Source::CHAN1,
             format: Format::ASC,
-            max_memory_size: MaxMemorySize::new(Mode::MAX, memory_depth),
-            mode: Mode::MAX
+            max_memory_size: MaxMemorySize::new(Mode::NORM, 0),
+            acquisition_points: 0,
+            mode: Mode::NORM,
+            preamble: None,
+        }
+    }
+
+    // Takes the scaling (and the transfer format, should it have changed) from `preamble`.
+    pub(crate) fn apply_preamble(&mut self, preamble: Preamble) {
+        self.origin = TwoDiv { x: preamble.x_origin as f32, y: preamble.y_origin as f32 };
+        self.reference = TwoDiv { x: preamble.x_reference as f32, y: preamble.y_reference as f32 };
+        self.increment = TwoDiv { x: preamble.x_increment as f32, y: preamble.y_increment as f32 };
+        if preamble.format != self.format {
+            self.apply_format(preamble.format);
+        }
+        self.preamble = Some(preamble);
+    }
+
+    // Checks that the scope may be in `mode` and records it with its memory size.
+    pub(crate) fn apply_mode(&mut self, mode: Mode, sweep: SWEep) -> Result<()> {
+        if (mode == Mode::MAX || mode == Mode::RAW) && sweep != SWEep::SING {
+            return Err(Error::CanNotChangeMode(mode));
+        }
+        self.max_memory_size = MaxMemorySize::new(mode, self.acquisition_points);
+        self.mode = mode;
+        Ok(())
+    }
+
+    // Records how many points an acquisition holds: the configured depth, capped at the
+    // share of the model's `max_memory_depth` the enabled channels get (all of it for one
+    // channel, half for two, a quarter for three or four).
+    pub(crate) fn apply_memory_depth(&mut self, configured: u32, enabled_channels: usize, max_memory_depth: u32) {
+        let share = match enabled_channels {
+            0 | 1 => 1,
+            2 => 2,
+            _ => 4,
         };
-        cmd.get_origin()?;
-        cmd.get_reference()?;
-        cmd.get_increment()?;
-        cmd.get_mode(trigger)?;
-        cmd.get_source()?;
-        cmd.get_format()?;
-        cmd.start(1)?;
-        cmd.stop(1)?;
-        Ok(cmd)
+        self.acquisition_points = configured.min(max_memory_depth / share);
+        self.max_memory_size = MaxMemorySize::new(self.mode, self.acquisition_points);
+    }
 
+    pub(crate) fn check_range(&self, range: u32) -> Result<()> {
+        if range > self.max_memory_size.to_u32() {
+            return Err(Error::ExceededMaxMemorySize(self.max_memory_size));
+        }
+        Ok(())
     }
 
-    pub fn set_source(&mut self, source: Source) ->  Result<()>{
-        let command = format!(":WAVeform:SOURce {}\n", source);
-        self.device.write_scip_cmd(command.as_bytes())?;
+    pub(crate) fn apply_format(&mut self, format: Format) {
+        self.max_transfer_size = MaxTransferSize::from(format);
+        self.data = RecieveData::new(self.max_transfer_size);
+        self.format = format;
+    }
+
+    pub(crate) fn check_start_point(&self, start_point: u32) -> Result<()> {
+        if start_point > self.max_memory_size.to_u32() {
+            return Err(Error::ExceededMaxMemorySize(self.max_memory_size));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_stop_point(&self, stop_point: u32) -> Result<()> {
+        if stop_point > self.max_memory_size.to_u32() {
+            return Err(Error::ExceededMaxMemorySize(self.max_memory_size));
+        }
+        if stop_point < self.start_point {
+            return Err(Error::StartIsGreaterThanStop(self.start_point, stop_point));
+        }
+        if (stop_point - self.start_point) > self.max_transfer_size as u32 {
+            return Err(Error::ExceedeMaxTransferSize(self.max_transfer_size));
+        }
         Ok(())
     }
+}
+
+// The memory sizes are those of the analog channels: the digital channels and MATH are
+// read in NORMal mode only.
+pub(crate) fn check_source(mode: Mode, source: Source, model: &str) -> Result<()> {
+    if mode != Mode::NORM && source.channel().is_none() {
+        return Err(Error::Unsupported { feature: format!("Reading {} in {} mode", source, mode), model: model.to_string() });
+    }
+    Ok(())
+}
+
+// The `start..=stop` point ranges (1-based) that read the first `range` points in
+// transfers of at most `max_transfer_size` points.
+pub(crate) fn chunks(range: u32, max_transfer_size: MaxTransferSize) -> impl Iterator<Item = (u32, u32)> {
+    let d = max_transfer_size as u32;
+    (0..range.div_ceil(d)).map(move |n| {
+        let start_n = n * d + 1;
+        (start_n, (start_n + d - 1).min(range))
+    })
+}
+
+pub const SOURCE: Property<Source> = Property::new(":WAVeform:SOURce");
+pub const MODE: Property<Mode> = Property::new(":WAVeform:MODE");
+pub const FORMAT: Property<Format> = Property::new(":WAVeform:FORMat");
+pub const START: Property<u32> = Property::new(":WAVeform:STARt").range(1, MemoryDepth::DS1102Z_E as u32);
+pub const STOP: Property<u32> = Property::new(":WAVeform:STOP").range(1, MemoryDepth::DS1102Z_E as u32);
+
+pub struct WAVeformCommands<'a, V: Visa> {
+    scope: &'a mut Ds1000z<V>,
+}
+
+impl<V: Visa> Deref for WAVeformCommands<'_, V> {
+    type Target = WAVeformState;
 
-    pub fn get_source(&mut self) -> Result<()> {
-        self.device.write_scip_cmd(b":WAVeform:SOURce?\n")?;
-        let buffer :String = self.device.read_result(1)?;
-        self.source = buffer.parse()?;
+    fn deref(&self) -> &WAVeformState {
+        &self.scope.waveform_state
+    }
+}
+
+impl<V: Visa> DerefMut for WAVeformCommands<'_, V> {
+    fn deref_mut(&mut self) -> &mut WAVeformState {
+        &mut self.scope.waveform_state
+    }
+}
+
+impl<'a, V: Visa> WAVeformCommands<'a, V> {
+    pub(crate) fn new(scope: &'a mut Ds1000z<V>) -> WAVeformCommands<'a, V> {
+        WAVeformCommands { scope }
+    }
+
+    pub(crate) fn init(&mut self) -> Result<()> {
+        self.get_preamble()?;
+        self.get_mode()?;
+        self.get_source()?;
+        self.get_format()?;
+        self.start(1)?;
+        self.stop(1)?;
         Ok(())
     }
 
-    pub fn set_mode(&mut self, mode: Mode) -> Result<()> {
-        let command = format!(":WAVeform:MODE {}\n", mode);
-        self.device.write_scip_cmd(command.as_bytes())?;
+    // Reads back everything `WAVeformState` mirrors.
+    pub fn refresh(&mut self) -> Result<()> {
+        self.get_preamble()?;
+        self.get_mode()?;
+        self.get_source()?;
+        self.get_format()?;
+        self.get_start_point()?;
+        self.get_stop_point()?;
         Ok(())
+    }
+
+    pub fn set_source(&mut self, source: Source) ->  Result<()>{
+        SOURCE.set(self.scope, source)
+    }
+
+    pub fn get_source(&mut self) -> Result<Source> {
+        SOURCE.get(self.scope)
+    }
+
+    // The `current_*` values come from the settings cache, or from the scope when the
+    // cache does not hold them.
+    pub fn current_source(&mut self) -> Result<Source> {
+        SOURCE.current(self.scope)
+    }
+
+    pub fn set_mode(&mut self, mode: Mode) -> Result<()> {
+        MODE.set(self.scope, mode)
     }       
 
-    pub fn get_mode(&mut self, trigger :&TRIGgerCommand::TRIGgerCommand) -> Result<()> {
-        self.device.write_scip_cmd(b":WAVeform:MODE?\n")?;
-        let buffer :String = self.device.read_result(1)?;
-        let mode: Mode = buffer.parse()?;
-        if (mode == Mode::MAX || mode == Mode::RAW) && trigger.sweep != TRIGgerCommand::SWEep::SING{
-            return Err(Error::CanNotChangeMode(mode));
+    pub fn get_mode(&mut self) -> Result<Mode> {
+        let mode = MODE.get(self.scope)?;
+        if mode != Mode::NORM {
+            self.get_memory_depth()?;
         }
-        self.max_memory_size = MaxMemorySize::new(mode, self.memory_depth);
-        self.mode = mode;
-        return Ok(());
+        let sweep = SWEEP.current(self.scope)?;
+        self.apply_mode(mode, sweep)?;
+        Ok(mode)
+    }
+
+    pub fn current_mode(&mut self) -> Result<Mode> {
+        MODE.current(self.scope)
+    }
+
+    // The points that can be read in the current mode.
+    pub fn max_memory_size(&mut self) -> Result<MaxMemorySize> {
+        let mode = MODE.current(self.scope)?;
+        if mode != Mode::NORM {
+            let source = SOURCE.current(self.scope)?;
+            check_source(mode, source, &self.scope.identity()?.model)?;
+            if !self.memory_depth_cached()? {
+                self.get_memory_depth()?;
+            }
+        }
+        let sweep = SWEEP.current(self.scope)?;
+        self.apply_mode(mode, sweep)?;
+        Ok(self.max_memory_size)
     }
 
-    pub fn mode(&mut self, mode: Mode, trigger :&TRIGgerCommand::TRIGgerCommand) -> Result<()> {
+    pub fn mode(&mut self, mode: Mode) -> Result<()> {
         self.set_mode(mode)?;
-        self.get_mode(trigger)
+        self.get_mode()?;
+        Ok(())
     }
 
     pub fn set_format(&mut self, format: Format) -> Result<()> {
-        let command = format!(":WAVeform:FORMat {}\n", format);
-        self.device.write_scip_cmd(command.as_bytes())?;
-        Ok(())
+        FORMAT.set(self.scope, format)
     }   
 
-    pub fn get_format(&mut self) -> Result<()> {
-        self.device.write_scip_cmd(b":WAVeform:FORMat?\n")?;
-        let buffer: String = self.device.read_result(1)?;
-        let format: Format = buffer.parse()?;
-        self.max_transfer_size = MaxTransferSize::from(format);
-        self.data = RecieveData::new(self.max_transfer_size);
-        self.format = format;
-        Ok(())
+    pub fn get_format(&mut self) -> Result<Format> {
+        let format = FORMAT.get(self.scope)?;
+        self.apply_format(format);
+        Ok(format)
+    }
+
+    pub fn current_format(&mut self) -> Result<Format> {
+        let format = FORMAT.current(self.scope)?;
+        if format != self.format {
+            self.apply_format(format);
+        }
+        Ok(format)
+    }
+
+    pub fn max_transfer_size(&mut self) -> Result<MaxTransferSize> {
+        Ok(MaxTransferSize::from(self.current_format()?))
     }
 
     pub fn format(&mut self, format: Format) -> Result<()> {
-        println!("format start");
         self.set_format(format)?;
-        println!("format mid");
         self.get_format()?;
-        println!("format end");
         Ok(())
     }
 
+    // Reads `:ACQuire:MDEPth?` and which of the model's channels are enabled, and returns
+    // the number of points an acquisition holds. With the depth on AUTO that is the
+    // sample rate times the 12 divisions on screen.
+    pub fn get_memory_depth(&mut self) -> Result<u32> {
+        let channels = self.scope.analog_channels()?;
+        let max_memory_depth = self.scope.max_memory_depth()?;
+        let mut batch = self.scope.batch();
+        let depth = batch.query::<String>(":ACQuire:MDEPth?");
+        let displays: Vec<Reply<String>> = CHANnelCommand::DISPLAY[..channels as usize].iter()
+            .map(|display| batch.query(&format!("{}?", display.header())))
+            .collect();
+        let replies = batch.send()?;
+        self.scope.cache.store(":ACQuire:MDEPth", replies.text(depth)?);
+        for (display, reply) in CHANnelCommand::DISPLAY.iter().zip(&displays) {
+            self.scope.cache.store(display.header(), replies.text(*reply)?);
+        }
+        let configured = match replies.text(depth)? {
+            "AUTO" => {
+                let mut batch = self.scope.batch();
+                let sample_rate = batch.query::<f64>(":ACQuire:SRATe?");
+                let scale = batch.query::<f64>(":TIMebase:SCALe?");
+                let auto = batch.send()?;
+                self.scope.cache.store(":ACQuire:SRATe", auto.text(sample_rate)?);
+                self.scope.cache.store(":TIMebase:SCALe", auto.text(scale)?);
+                (auto.get(sample_rate)? * auto.get(scale)? * 12.0).round() as u32
+            }
+            text => firmware::parse_u32(text, self.scope.quirks())?,
+        };
+        let mut enabled_channels = 0;
+        for display in displays {
+            if bool::parse(replies.text(display)?)? {
+                enabled_channels += 1;
+            }
+        }
+        self.apply_memory_depth(configured, enabled_channels, max_memory_depth);
+        Ok(self.acquisition_points)
+    }
+
+    // Whether the cache still holds every setting `get_memory_depth` depends on.
+    fn memory_depth_cached(&mut self) -> Result<bool> {
+        let channels = self.scope.analog_channels()?;
+        let cache = &self.scope.cache;
+        let mut headers = vec![":ACQuire:MDEPth"];
+        if cache.get(":ACQuire:MDEPth") == Some("AUTO") {
+            headers.extend([":ACQuire:SRATe", ":TIMebase:SCALe"]);
+        }
+        headers.extend(CHANnelCommand::DISPLAY[..channels as usize].iter().map(|display| display.header()));
+        Ok(headers.into_iter().all(|header| cache.get(header).is_some()))
+    }
+
+    pub fn get_preamble(&mut self) -> Result<Preamble> {
+        let preamble = Preamble::parse(&self.scope.query(":WAVeform:PREamble?")?, self.scope.quirks())?;
+        self.apply_preamble(preamble);
+        Ok(preamble)
+    }
+
     pub fn get_xorigin(&mut self) -> Result<()> {
-        self.device.write_scip_cmd(b":WAVeform:XORigin?\n")?;
-        let buffer: String = self.device.read_result(1)?;
-        self.origin.x = buffer.parse::<f32>()?;
+        let buffer: String = self.scope.query(":WAVeform:XORigin?")?;
+        self.origin.x = parse_number(&buffer)? as f32;
         Ok(())
     }
 
     pub fn get_yorigin(&mut self) -> Result<()> {
-        self.device.write_scip_cmd(b":WAVeform:YORigin?\n")?;
-        let buffer: String = self.device.read_result(1)?;
-        self.origin.y = buffer.parse::<f32>()?;
+        let buffer: String = self.scope.query(":WAVeform:YORigin?")?;
+        self.origin.y = parse_number(&buffer)? as f32;
         Ok(())
     }
 
     pub fn get_origin(&mut self) -> Result<()> {
-        self.get_xorigin()?;
-        self.get_yorigin()?;
+        let mut batch = self.scope.batch();
+        let x = batch.query::<f32>(":WAVeform:XORigin?");
+        let y = batch.query::<f32>(":WAVeform:YORigin?");
+        let replies = batch.send()?;
+        self.origin = TwoDiv { x: replies.get(x)?, y: replies.get(y)? };
         Ok(())
     }
 
     pub fn get_xreference(&mut self) -> Result<()> {
-        self.device.write_scip_cmd(b":WAVeform:XREFerence?\n")?;
-        let buffer: String = self.device.read_result(1)?;
-        self.reference.x = buffer.parse::<f32>()?;
+        let buffer: String = self.scope.query(":WAVeform:XREFerence?")?;
+        self.reference.x = parse_number(&buffer)? as f32;
         Ok(())
     }
 
     pub fn get_yreference(&mut self) -> Result<()> {
-        self.device.write_scip_cmd(b":WAVeform:YREFerence?\n")?;
-        let buffer: String = self.device.read_result(1)?;
-        self.reference.y = buffer.parse::<f32>()?;
+        let buffer: String = self.scope.query(":WAVeform:YREFerence?")?;
+        self.reference.y = parse_number(&buffer)? as f32;
         Ok(())
     }
 
     pub fn get_reference(&mut self) -> Result<()> {
-        self.get_xreference()?;
-        self.get_yreference()?;
+        let mut batch = self.scope.batch();
+        let x = batch.query::<f32>(":WAVeform:XREFerence?");
+        let y = batch.query::<f32>(":WAVeform:YREFerence?");
+        let replies = batch.send()?;
+        self.reference = TwoDiv { x: replies.get(x)?, y: replies.get(y)? };
         Ok(())
     }
 
     pub fn get_xincrement(&mut self) -> Result<()> {
-        self.device.write_scip_cmd(b":WAVeform:XINCrement?\n")?;
-        let buffer: String = self.device.read_result(1)?;
-        self.increment.x = buffer.parse::<f32>()?;
+        let buffer: String = self.scope.query(":WAVeform:XINCrement?")?;
+        self.increment.x = parse_number(&buffer)? as f32;
         Ok(())
     }
 
     pub fn get_yincrement(&mut self) -> Result<()> {
-        self.device.write_scip_cmd(b":WAVeform:YINCrement?\n")?;
-        let buffer: String = self.device.read_result(1)?;
-        self.increment.y = buffer.parse::<f32>()?;
+        let buffer: String = self.scope.query(":WAVeform:YINCrement?")?;
+        self.increment.y = parse_number(&buffer)? as f32;
         Ok(())
     }
 
     pub fn get_increment(&mut self) -> Result<()> {
-        self.get_xincrement()?;
-        self.get_yincrement()?;
+        let mut batch = self.scope.batch();
+        let x = batch.query::<f32>(":WAVeform:XINCrement?");
+        let y = batch.query::<f32>(":WAVeform:YINCrement?");
+        let replies = batch.send()?;
+        self.increment = TwoDiv { x: replies.get(x)?, y: replies.get(y)? };
         Ok(())
     }
 
-    pub fn get_start_point(&mut self) -> Result<()> {
-        self.device.write_scip_cmd(b":WAVeform:STARt?\n")?;
-        let buffer: String = self.device.read_result(1)?;
-        println!("startpoint = {}", buffer);
-        self.start_point = buffer.parse::<u32>()?;
-        Ok(())
+    pub fn get_start_point(&mut self) -> Result<u32> {
+        self.start_point = START.get(self.scope)?;
+        Ok(self.start_point)
+    }
+
+    pub fn current_start_point(&mut self) -> Result<u32> {
+        START.current(self.scope)
     }
 
     pub fn set_start_point(&mut self, start_point: u32) -> Result<()> {
-        if start_point > self.max_memory_size.to_u32() {
-            return Err(Error::ExceededMaxMemorySize(self.max_memory_size));
-        }
-        let command = format!(":WAVeform:STARt {}\n", start_point);
-        self.device.write_scip_cmd(command.as_bytes())?;
-        Ok(())
+        self.max_memory_size()?;
+        self.check_start_point(start_point)?;
+        START.set(self.scope, start_point)
     }
 
     pub fn start(&mut self, start_point: u32) -> Result<()> {
@@ -550,27 +688,21 @@ impl WAVeformCommands {
         Ok(())
     }
 
-    pub fn get_stop_point(&mut self) -> Result<()> {
-        self.device.write_scip_cmd(b":WAVeform:STOP?\n")?;
-        let buffer: String = self.device.read_result(1)?;
-        self.stop_point = buffer.parse::<u32>()?;
-        Ok(())
+    pub fn get_stop_point(&mut self) -> Result<u32> {
+        self.stop_point = STOP.get(self.scope)?;
+        Ok(self.stop_point)
+    }
+
+    pub fn current_stop_point(&mut self) -> Result<u32> {
+        STOP.current(self.scope)
     }
 
     pub fn set_stop_point(&mut self, stop_point: u32) -> Result<()> {
-        if stop_point > self.max_memory_size.to_u32() {
-            return Err(Error::ExceededMaxMemorySize(self.max_memory_size));
-        }
-        if (stop_point < self.start_point) {
-            return Err(Error::StartIsGreaterThanStop(self.start_point, self.stop_point));
-        }
-        if (stop_point - self.start_point) > self.max_transfer_size as u32 {
-            println!("erro stop_point = {}, start = {}, max = {}", stop_point, self.start_point, self.max_transfer_size);
-            return Err(Error::ExceedeMaxTransferSize(self.max_transfer_size));
-        }
-        let command = format!(":WAVeform:STOP {}\n", stop_point);
-        self.device.write_scip_cmd(command.as_bytes())?;
-        Ok(())
+        self.max_memory_size()?;
+        self.max_transfer_size()?;
+        self.start_point = START.current(self.scope)?;
+        self.check_stop_point(stop_point)?;
+        STOP.set(self.scope, stop_point)
     }
 
     pub fn stop(&mut self, stop_point: u32) -> Result<()> {
@@ -580,20 +712,20 @@ impl WAVeformCommands {
     }
 
     pub fn get_data(&mut self) -> Result<()> {
-        self.device.write_scip_cmd(b":WAVeform:DATA?\n")?;
-        match self.format {
-            Format::BYTE => {
-                println!("fadasfafdad");
-                self.device.read_bytes_u8(10, &mut self.data)?;
-            }
-            Format::WORD => {
-                self.device.read_bytes_u16(10, &mut self.data)?;
-            }
-            _ => {
-                return Ok(());
-            }
+        let format = self.current_format()?;
+        if format == Format::ASC {
+            return Ok(());
+        }
+        let payload = self.scope.query_block(":WAVeform:DATA?")?;
+        let full_block = format == Format::BYTE && payload.len() == MaxTransferSize::BYTE as usize;
+        let trailing = firmware::trailing_block_bytes(self.scope.quirks(), full_block);
+        if trailing > 0 {
+            self.scope.device.discard(trailing)?;
+        }
+        match format {
+            Format::BYTE => store_bytes_u8(payload, &mut self.data),
+            _ => store_bytes_u16(payload, &mut self.data),
         }
-        Ok(())
     }
 
 
@@ -601,16 +733,11 @@ impl WAVeformCommands {
 }
 
 
-pub fn get_data(range: u32,  waveform: &mut WAVeformCommands, convert_data: &mut ConvertData) -> Result<()>{
-    let count = (range + waveform.max_transfer_size as u32 - 1) / waveform.max_transfer_size as u32;
-    println!("count = {}", count);
-    for i in 0..count as usize {
-        let a1: u32 = 1;
-        let n: u32 = i as u32;
-        let d: u32 = waveform.max_transfer_size as u32;
-        let start_n: u32 = n * d + a1;
-        let end_n: u32 = ((start_n + d - 1).min(range)).min(start_n + d - 1);
-        println!("start = {}, end = {}", start_n, end_n);
+pub fn get_data<V: Visa>(range: u32,  waveform: &mut WAVeformCommands<V>, convert_data: &mut ConvertData) -> Result<()>{
+    waveform.max_memory_size()?;
+    waveform.check_range(range)?;
+    for (start_n, end_n) in chunks(range, waveform.max_transfer_size()?) {
+        waveform.get_preamble()?;
         waveform.start(start_n)?;
         waveform.stop(end_n)?;
         waveform.get_data()?;
@@ -625,35 +752,154 @@ pub fn get_data(range: u32,  waveform: &mut WAVeformCommands, convert_data: &mut
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::io::Write;
+    use crate::simulator::Simulator;
     #[test]
     fn test_set_mode() {
-        let address = "169.254.245.109:5555";
-        let memory_depth = MemoryDepth::DS1102Z_E;
-        let device_trigger = match std::net::TcpStream::connect(address) {
-            Ok(stream) => stream,
-            Err(e) => {
-                panic!("Failed to connect to device: {}", e);
-            }
-        };
-        let device_wafeform: std::net::TcpStream = device_trigger.try_clone().unwrap();
-        let trigger_command = TRIGgerCommand::TRIGgerCommand::new(device_trigger).unwrap();
-        //println!("{:?}", trigger_command);
-        let mut waveform_commands = WAVeformCommands::new(device_wafeform, memory_depth, &trigger_command).unwrap();
+        let mut device = Simulator::new();
+        device.instrument_mut().memory_depth = Some(300000);
+        let mut scope = Ds1000z::new(device).unwrap();
+        scope.trigger().sweep(SWEep::SING).unwrap();
+        let mut convert_data = ConvertData::new();
+        let range = 300000;
         {
-            waveform_commands.format(Format::BYTE);
-            waveform_commands.mode(Mode::RAW, &trigger_command);
-            let mut convert_data = ConvertData::new();
-            let range = 24000000;
+            let mut waveform_commands = scope.waveform();
+            waveform_commands.format(Format::BYTE).unwrap();
+            waveform_commands.mode(Mode::RAW).unwrap();
             get_data(range, &mut waveform_commands, &mut convert_data).unwrap();
-            let mut file = std::fs::File::create("output.bin").unwrap();
-            println!("convert_data count = {}", convert_data.count);
-            if let Some(slice) = convert_data.data.get(0..convert_data.count as usize) {
-                for d in slice {
-                    let s = format!("{}, {}\n", d.x, d.y);
-                    file.write_all(s.as_bytes()).unwrap();
-                }
-            }
+        }
+        assert_eq!(convert_data.count, range);
+        let instrument = scope.device().instrument();
+        for i in (0..range).step_by(997) {
+            let expected = instrument.voltage(Source::CHAN1, i) as f32;
+            let actual = convert_data.data[i as usize].y;
+            assert!((actual - expected).abs() <= 0.021, "point {}: {} != {}", i, actual, expected);
+        }
+        // x keeps increasing across the boundary of the two chunks
+        let increment = scope.waveform().increment.x;
+        let [before, last, first, after] = [249998, 249999, 250000, 250001].map(|i| convert_data.data[i].x);
+        assert!(increment > 0.0);
+        for (a, b) in [(before, last), (last, first), (first, after)] {
+            assert!((b - a - increment).abs() <= increment * 1e-2, "{} -> {}, increment {}", a, b, increment);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_mode_requires_single_sweep() {
+        let mut scope = Ds1000z::new(Simulator::new()).unwrap();
+        match scope.waveform().mode(Mode::RAW) {
+            Err(Error::CanNotChangeMode(Mode::RAW)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_raw_memory_depth() {
+        let mut scope = Ds1000z::new(Simulator::new()).unwrap();
+        scope.trigger().sweep(SWEep::SING).unwrap();
+        let mut waveform = scope.waveform();
+        waveform.mode(Mode::RAW).unwrap();
+        assert_eq!(waveform.max_memory_size().unwrap(), MaxMemorySize::RAW(24_000_000));
+        waveform.mode(Mode::MAX).unwrap();
+        assert_eq!(waveform.max_memory_size().unwrap(), MaxMemorySize::MAX(24_000_000));
+
+        scope.channel(2).unwrap().set_display(true).unwrap();
+        assert_eq!(scope.waveform().get_memory_depth().unwrap(), 12_000_000);
+        scope.channel(3).unwrap().set_display(true).unwrap();
+        assert_eq!(scope.waveform().get_memory_depth().unwrap(), 6_000_000);
+        scope.write(":ACQuire:MDEPth 300000").unwrap();
+        let mut waveform = scope.waveform();
+        waveform.mode(Mode::RAW).unwrap();
+        assert_eq!(waveform.max_memory_size().unwrap(), MaxMemorySize::RAW(300000));
+        // the sample rate only matters for AUTO
+        assert_eq!(scope.cache().get(":ACQuire:SRATe"), None);
+        let mut waveform = scope.waveform();
+
+        waveform.start(1).unwrap();
+        assert!(matches!(waveform.set_stop_point(300001), Err(Error::ExceededMaxMemorySize(MaxMemorySize::RAW(300000)))));
+        waveform.start(1000).unwrap();
+        assert!(matches!(waveform.set_stop_point(999), Err(Error::StartIsGreaterThanStop(1000, 999))));
+        let mut convert_data = ConvertData::new();
+        assert!(matches!(get_data(300001, &mut waveform, &mut convert_data), Err(Error::ExceededMaxMemorySize(_))));
+        waveform.set_source(Source::D3).unwrap();
+        assert!(matches!(get_data(1000, &mut waveform, &mut convert_data), Err(Error::Unsupported { feature, .. }) if feature == "Reading D3 in RAW mode"));
+    }
+
+    #[test]
+    fn test_firmware_quirks() {
+        use crate::firmware::{QuirkEntry, QuirkRegistry};
+        let quirks = [Quirk::Nr3Integers, Quirk::TrailingBlockBytes(2)];
+        let mut device = Simulator::new();
+        device.instrument_mut().memory_depth = Some(300000);
+        device.instrument_mut().quirks = quirks.to_vec();
+        let mut registry = QuirkRegistry::new();
+        for quirk in quirks {
+            registry.register(QuirkEntry { model: Some("DS1104Z".to_string()), from: None, until: "00.04.05".parse().ok(), quirk });
+        }
+        let mut scope = Ds1000z::with_quirks(device, registry).unwrap();
+        assert_eq!(scope.quirks(), quirks);
+        scope.trigger().sweep(SWEep::SING).unwrap();
+        let mut convert_data = ConvertData::new();
+        {
+            let mut waveform = scope.waveform();
+            waveform.mode(Mode::RAW).unwrap();
+            assert_eq!(waveform.max_memory_size().unwrap(), MaxMemorySize::RAW(300000));
+            get_data(300000, &mut waveform, &mut convert_data).unwrap();
+        }
+        assert_eq!(convert_data.count, 300000);
+        assert_eq!(START.get(&mut scope).unwrap(), 250001);
+        assert_eq!(scope.query("*OPC?").unwrap(), "1");
+    
+        // without the registry the NR3 integers do not parse and the trailing bytes are
+        // taken for the next reply
+        let mut device = Simulator::new();
+        device.instrument_mut().quirks = vec![Quirk::Nr3Integers];
+        assert!(matches!(Ds1000z::new(device), Err(Error::ParseError { .. })));
+        let mut device = Simulator::new();
+        device.instrument_mut().memory_depth = Some(300000);
+        device.instrument_mut().quirks = vec![Quirk::TrailingBlockBytes(2)];
+        let mut scope = Ds1000z::new(device).unwrap();
+        scope.trigger().sweep(SWEep::SING).unwrap();
+        let mut waveform = scope.waveform();
+        waveform.mode(Mode::RAW).unwrap();
+        assert!(matches!(get_data(300000, &mut waveform, &mut ConvertData::new()), Err(Error::ParseError { .. })));
+    }
+
+    #[test]
+    fn test_mnemonics() {
+        assert_eq!("chan1".parse::<Source>().unwrap(), Source::CHAN1);
+        assert_eq!("CHANnel2".parse::<Source>().unwrap(), Source::CHAN2);
+        assert_eq!("NORMal".parse::<Mode>().unwrap(), Mode::NORM);
+        assert_eq!("ascii".parse::<Format>().unwrap(), Format::ASC);
+        assert!(matches!("CHANNEL".parse::<Source>(), Err(Error::ParseError { .. })));
+        assert_eq!(Source::CHAN3.to_string(), "CHAN3");
+        assert_eq!(Format::ALL, [Format::WORD, Format::BYTE, Format::ASC]);
+        assert_eq!(Source::ALL.len(), 21);
+        for source in Source::ALL {
+            assert_eq!(source.to_string().parse::<Source>().unwrap(), *source);
+        }
+
+        let mut scope = Ds1000z::new(Simulator::new()).unwrap();
+        scope.write(":WAVeform:FORMat word").unwrap();
+        let mut waveform = scope.waveform();
+        assert_eq!(waveform.get_format().unwrap(), Format::WORD);
+    }
+
+    #[test]
+    fn test_preamble() {
+        let preamble: Preamble = "0,2,6000000,1,1.000000e-09,-3.000000e-03,0,4.000000e-02,-10,127\n".parse().unwrap();
+        assert_eq!((preamble.format, preamble.mode, preamble.points, preamble.count), (Format::BYTE, Mode::RAW, 6000000, 1));
+        assert_eq!((preamble.x_increment, preamble.x_origin, preamble.y_origin, preamble.y_reference), (1e-9, -3e-3, -10.0, 127.0));
+        assert!(matches!("0,2,6000000".parse::<Preamble>(), Err(Error::ParseError { .. })));
+        assert!(matches!("7,2,6000000,1,1e-9,0,0,0.04,0,127".parse::<Preamble>(), Err(Error::ParseError { .. })));
+
+        let mut scope = Ds1000z::new(Simulator::new()).unwrap();
+        scope.channel(1).unwrap().set_scale(2.0).unwrap();
+        scope.channel(1).unwrap().set_offset(0.4).unwrap();
+        let mut waveform = scope.waveform();
+        let preamble = waveform.get_preamble().unwrap();
+        assert_eq!(waveform.preamble, Some(preamble));
+        assert_eq!(waveform.increment.y, 0.08);
+        assert_eq!(waveform.origin.y, 5.0);
+        assert_eq!(waveform.reference.y, 127.0);
+    }
+}
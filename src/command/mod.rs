@@ -1,2 +1,6 @@
+#[allow(non_snake_case)]
+pub mod CHANnelCommand;
+#[allow(non_snake_case)]
 pub mod WAVeformCommand;
-pub mod TRIGgerCommand;
\ No newline at end of file
+#[allow(non_snake_case)]
+pub mod TRIGgerCommand;
@@ -1,149 +1,265 @@
-use std::io::{self, Write, Read};
-use crate::command::TRIGgerCommand;
+use std::io::{self, BufRead, Write, Read};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use crate::block;
+use crate::error::{Error, Result};
 use crate::command::WAVeformCommand::RecieveData;
 use crate::command::WAVeformCommand::MaxTransferSize;
 
+// Any buffered byte stream that can be written to and read from speaks SCPI through this
+// trait (raw TCP socket, serial bridge, in-memory mock, ...). Wrap unbuffered streams in
+// `BufStream`. Read timeouts are a property of the underlying stream, e.g.
+// `TcpStream::set_read_timeout`, and are not managed here.
 pub trait Visa{
-    fn write_scip_cmd(&mut self, buf: &[u8]) -> std::io::Result<()>;
-    fn read_result(&mut self, timeout_s: u64) -> std::io::Result<String>;
-    fn read_result2(&mut self) -> std::io::Result<String>;
-    fn read_bytes_u8(&mut self, timeout_s: u64, data: &mut RecieveData) -> std::result::Result<(), Box<dyn std::error::Error>>;
-    fn read_bytes_u16(&mut self, timeout_s: u64, data: &mut RecieveData) -> std::result::Result<(), Box<dyn std::error::Error>>;
+    fn write_scip_cmd(&mut self, buf: &[u8]) -> Result<()>;
+    fn read_result(&mut self) -> Result<String>;
+    fn read_result2(&mut self) -> Result<String>;
+    fn read_block(&mut self) -> Result<Vec<u8>>;
+
+    // Device clear: aborts the current operation and discards pending output. Only
+    // message based transports (VXI-11) have one; on byte streams it does nothing.
+    fn clear(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    // Replaces the connection with a fresh one to the same instrument. Transports that
+    // can not fail with `io::ErrorKind::Unsupported`.
+    fn reconnect(&mut self) -> Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "the transport can not reconnect").into())
+    }
+
+    // Skips `len` bytes the instrument sent after a reply (see `firmware::Quirk`). Message
+    // based transports drop them with the rest of the message, so by default it does
+    // nothing.
+    fn discard(&mut self, _len: usize) -> Result<()> {
+        Ok(())
+    }
+
+    fn read_bytes_u8(&mut self, data: &mut RecieveData) -> Result<()> {
+        store_bytes_u8(self.read_block()?, data)
+    }
+
+    fn read_bytes_u16(&mut self, data: &mut RecieveData) -> Result<()> {
+        store_bytes_u16(self.read_block()?, data)
+    }
+}
+
+// The text of a response message, without its '\n' (or "\r\n") terminator.
+pub(crate) fn response_text(mut message: Vec<u8>) -> Result<String> {
+    while matches!(message.last(), Some(b'\n' | b'\r')) {
+        message.pop();
+    }
+    String::from_utf8(message).map_err(|err| Error::ProtocolError(format!("response is not valid UTF-8: {}", err)))
+}
+
+// Moves a BYTE waveform block into the receive buffer.
+pub(crate) fn store_bytes_u8(payload: Vec<u8>, data: &mut RecieveData) -> Result<()> {
+    match data {
+        RecieveData::BYTE(ref mut vec) => {
+            if payload.len() > MaxTransferSize::BYTE as usize {
+                return Err(Error::ExceedeMaxTransferSize(MaxTransferSize::BYTE));
+            }
+            *vec = payload;
+        }
+        _ => return Err(Error::InvalidArgument("the receive buffer does not match the transfer format".to_string())),
+    }
+    Ok(())
 }
 
-impl Visa for std::net::TcpStream {
-    fn write_scip_cmd(&mut self, buf: &[u8]) -> std::io::Result<()> {
+// Decodes a WORD waveform block (little endian) into the receive buffer.
+pub(crate) fn store_bytes_u16(payload: Vec<u8>, data: &mut RecieveData) -> Result<()> {
+    match data {
+        RecieveData::WORD(ref mut vec) => {
+            if !payload.len().is_multiple_of(2) {
+                return Err(Error::ProtocolError(format!("WORD block of odd length {}", payload.len())));
+            }
+            if payload.len() / 2 > MaxTransferSize::WORD as usize {
+                return Err(Error::ExceedeMaxTransferSize(MaxTransferSize::WORD));
+            }
+            vec.clear();
+            vec.extend(payload.chunks_exact(2).map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]])));
+        }
+        _ => return Err(Error::InvalidArgument("the receive buffer does not match the transfer format".to_string())),
+    }
+    Ok(())
+}
+
+impl<T: BufRead + Write> Visa for T {
+    fn write_scip_cmd(&mut self, buf: &[u8]) -> Result<()> {
         self.write_all(buf)?;
         self.flush()?;
-        return Ok(())
-    }
-    fn read_result(&mut self, timeout_s: u64) -> std::io::Result<String> {
-        self.set_read_timeout(Some(std::time::Duration::from_secs(timeout_s)))?;
-        let mut buffer = String::new();
-        let _ = self.read_to_string(&mut buffer);
-        if buffer.ends_with('\n') {
-            buffer.pop();
+        Ok(())
+    }
+
+    fn read_result(&mut self) -> Result<String> {
+        let mut line = Vec::new();
+        self.read_until(b'\n', &mut line)?;
+        if line.last() != Some(&b'\n') {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed before the response terminator").into());
         }
-        Ok(buffer)
+        response_text(line)
     }
 
-    fn read_result2(&mut self) -> std::io::Result<String> {
+    fn read_result2(&mut self) -> Result<String> {
         let mut buffer = [0; 5];
         let n  = self.read(&mut buffer)?;
         let response = String::from_utf8_lossy(&buffer[..n]);
         Ok(response.to_string())
     }
 
-    fn read_bytes_u8(&mut self, timeout_s: u64, data: &mut RecieveData) -> std::result::Result<(),Box<dyn std::error::Error>>{
-        let mut magic = [0; 1];
-        let mut header_len = [0; 1];
-        self.read(&mut magic)?;
-        self.read(&mut header_len)?;
+    fn read_block(&mut self) -> Result<Vec<u8>> {
+        Ok(block::read_block(self)?)
+    }
 
-        if magic[0] != b'#' {
-            return Err(Box::new(io::Error::new(io::ErrorKind::InvalidData, "Invalid header")));
-        }
-        let header_length: usize = (header_len[0] as char).to_digit(10)
-            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid header length"))? as usize;
-
-        let mut header = vec!(0; header_length);
-        self.read(&mut header)?;
-        let data_length_str: &str = std::str::from_utf8(&header)?;
-        let data_length: usize = data_length_str.parse()?;
-        match data {
-            RecieveData::BYTE(ref mut vec) => {
-                if (data_length) > MaxTransferSize::BYTE as usize{
-                    return Err(Box::new(io::Error::new(io::ErrorKind::InvalidData, "Incomplete data")));
-                }
-                //println!("data_length = {}, vec_len = {}", data_length, vec.len());
-                let mut total_read: usize = 0;
-                while total_read < data_length{
-                    let size = match self.read(&mut vec[total_read..]){
-                        Ok(s) => s,
-                        Err(err) => {
-                            println!("err {}", err);
-                            break;
-                        }
-                    };
-                    
-                    //println!("read_size = {:?}", size);
-                    total_read = total_read + size;
-                    if size == 0 {
-                        println!("0 dayo");
-                        break;
-                    }
-                    if total_read == MaxTransferSize::BYTE as usize {
-                        let mut dummy =  [0; 1];
-                        self.read(&mut dummy)?;
-                    }
-                }
-                //let size = self.read(vec)?;
-                //if size == 0 {
-                //    return Err(Box::new(io::Error::new(io::ErrorKind::UnexpectedEof, "No data received")));
-                //}
-                 
-                println!("size = {:?}", total_read);
-                if total_read != MaxTransferSize::BYTE as usize {
-                    if vec[total_read - 1] == b'\n' {
-                        vec[total_read -1] = 0;
-                    }
-                    else {
-                        return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "the suffix of the wavedata has to be '\n'")));
-                    }
-                }
-            }
-            _ => return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "Invalid data type"))),
-        }
+    fn discard(&mut self, len: usize) -> Result<()> {
+        let mut skipped = vec![0; len];
+        self.read_exact(&mut skipped)?;
         Ok(())
     }
+}
 
+const DEFAULT_BUF_SIZE: usize = 64 * 1024;
 
-    fn read_bytes_u16(&mut self, timeout_s: u64, data: &mut RecieveData) -> std::result::Result<(), Box<dyn std::error::Error>>{
-        let mut buffer = Vec::new();
-        self.set_read_timeout(Some(std::time::Duration::from_secs(timeout_s)))?;
-        self.read_to_end(&mut buffer)?;
-    
-        if buffer.len() < 2 || buffer[0] != b'#' {
-            return Err(Box::new(io::Error::new(io::ErrorKind::InvalidData, "Invalid header")));
-        }
-        let header_length: usize = (buffer[1] as char).to_digit(10)
-            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid header length"))? as usize;
-        if buffer.len() < 2 + header_length {
-            return Err(Box::new(io::Error::new(io::ErrorKind::InvalidData, "Incomplete header")));
+// Buffered wrapper around a raw byte stream. Responses are framed on the '\n' terminator,
+// so a query returns as soon as its reply is complete, and any bytes received past the
+// terminator stay buffered for the next read. A read timeout on the inner stream surfaces
+// as `io::ErrorKind::TimedOut` regardless of platform.
+#[derive(Debug)]
+pub struct BufStream<T> {
+    inner: T,
+    buf: Box<[u8]>,
+    pos: usize,
+    filled: usize,
+}
+
+impl<T> BufStream<T> {
+    pub fn new(inner: T) -> BufStream<T> {
+        BufStream::with_capacity(DEFAULT_BUF_SIZE, inner)
+    }
+
+    pub fn with_capacity(capacity: usize, inner: T) -> BufStream<T> {
+        BufStream {
+            inner,
+            buf: vec![0; capacity].into_boxed_slice(),
+            pos: 0,
+            filled: 0,
         }
-    
-        let data_length_str: &str = std::str::from_utf8(&buffer[2..2 + header_length])?;
-        let data_length: usize = data_length_str.parse()?;
-        if buffer.len() < 2 + header_length + data_length {
-            return Err(Box::new(io::Error::new(io::ErrorKind::InvalidData, "Incomplete data")));
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf[self.pos..self.filled]
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl BufStream<TcpStream> {
+    pub fn connect<A: ToSocketAddrs>(addr: A, read_timeout: Duration) -> io::Result<BufStream<TcpStream>> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(read_timeout))?;
+        stream.set_nodelay(true)?;
+        Ok(BufStream::new(stream))
+    }
+}
+
+fn map_timeout(err: io::Error) -> io::Error {
+    match err.kind() {
+        io::ErrorKind::WouldBlock => io::Error::new(io::ErrorKind::TimedOut, err),
+        _ => err,
+    }
+}
+
+impl<T: Read> Read for BufStream<T> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.filled && out.len() >= self.buf.len() {
+            return self.inner.read(out).map_err(map_timeout);
         }
-        let start = 2 + header_length;
-        let end = start + data_length * 2; 
-        let data_bytes = &buffer[start..end];
-        if data_bytes.len() % 2 != 0 {
-            return Err(Box::new(io::Error::new(io::ErrorKind::InvalidData, "Data length is not even")));
+        let available = self.fill_buf()?;
+        let n = available.len().min(out.len());
+        out[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<T: Read> BufRead for BufStream<T> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.filled {
+            self.filled = self.inner.read(&mut self.buf).map_err(map_timeout)?;
+            self.pos = 0;
         }
+        Ok(&self.buf[self.pos..self.filled])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.filled);
+    }
+}
+
+impl<T: Write> Write for BufStream<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
 
-        if data_length > MaxTransferSize::WORD as usize {
-            return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "invalid length")));
+    struct Loopback {
+        rx: Cursor<Vec<u8>>,
+        tx: Vec<u8>,
+    }
+
+    impl Read for Loopback {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.rx.read(buf)
         }
+    }
 
-        match data {
-            RecieveData::WORD(ref mut vec) => {
-                for (i, chunk) in data_bytes.chunks_exact(2).enumerate() {
-                    let num = u16::from_le_bytes([chunk[0], chunk[1]]);
-                    vec[i] = num;
-                }
-            }
-            _ => return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "Invalid data type"))),
+    impl Write for Loopback {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.tx.write(buf)
         }
 
-        //let mut u16_data = Vec::new();
-        //for chunk in data.chunks_exact(2) {
-        //    let num = u16::from_le_bytes([chunk[0], chunk[1]]);
-        //    u16_data.push(num);
-        //}
-        Ok(())
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
     }
 
+    #[test]
+    fn test_read_result_keeps_leftover() {
+        let mut stream = BufStream::new(Loopback {
+            rx: Cursor::new(b"AUTO\n1.000000e-03\r\nNORM".to_vec()),
+            tx: Vec::new(),
+        });
+        stream.write_scip_cmd(b":TRIGger:SWEep?\n").unwrap();
+        assert_eq!(stream.get_ref().tx, b":TRIGger:SWEep?\n");
+        assert_eq!(stream.read_result().unwrap(), "AUTO");
+        assert_eq!(stream.read_result().unwrap(), "1.000000e-03");
+        assert_eq!(stream.buffer(), b"NORM");
+        match stream.read_result() {
+            Err(Error::IoError(err)) => assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof),
+            other => panic!("unexpected {:?}", other),
+        }
+        match stream.reconnect() {
+            Err(Error::IoError(err)) => assert_eq!(err.kind(), io::ErrorKind::Unsupported),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
 }
@@ -0,0 +1,140 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::device::Visa;
+use crate::error::Result;
+use crate::identity::Identity;
+use crate::resource::{self, Resource, Timeouts};
+use crate::rpc::{self, XdrReader};
+use crate::vxi11::{self, Vxi11};
+
+// LAN discovery: a portmapper GETPORT for the VXI-11 core program is broadcast over UDP,
+// every host that answers with a port is asked for `*IDN?` over VXI-11, and the RIGOL
+// DS1000Z-family responders are returned.
+
+pub const RAW_SOCKET_PORT: u16 = 5555;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Discovered {
+    pub address: IpAddr,
+    pub vxi11_port: u16,
+    pub identity: Identity,
+}
+
+impl Discovered {
+    pub fn resource(&self) -> Resource {
+        Resource::Vxi11 { board: 0, host: self.address.to_string(), device: "inst0".to_string() }
+    }
+
+    // The raw SCPI socket every DS1000Z also listens on.
+    pub fn socket_resource(&self) -> Resource {
+        Resource::Socket { board: 0, host: self.address.to_string(), port: RAW_SOCKET_PORT }
+    }
+}
+
+// Broadcasts on the local network and collects answers for `wait`.
+pub fn discover(wait: Duration) -> Result<Vec<Discovered>> {
+    let broadcast = SocketAddr::from((Ipv4Addr::BROADCAST, rpc::PORTMAP_PORT));
+    discover_on(&[broadcast], wait, Timeouts::default())
+}
+
+// Like `discover`, sending the GETPORT call to each of `targets` (broadcast or unicast
+// portmapper addresses).
+pub fn discover_on(targets: &[SocketAddr], wait: Duration, timeouts: Timeouts) -> Result<Vec<Discovered>> {
+    let responders = find_responders(targets, wait)?;
+    let mut found: Vec<Discovered> = thread::scope(|scope| {
+        let handles: Vec<_> = responders.iter()
+            .map(|&(address, port)| scope.spawn(move || identify(address, port, timeouts).map(|identity| Discovered { address, vxi11_port: port, identity })))
+            .collect();
+        // hosts that refuse the link or do not answer `*IDN?` are not instruments we can use
+        handles.into_iter().filter_map(|handle| handle.join().ok()?.ok()).collect()
+    });
+    found.retain(|instrument| instrument.identity.is_ds1000z());
+    found.sort_by_key(|instrument| (instrument.address, instrument.vxi11_port));
+    Ok(found)
+}
+
+fn find_responders(targets: &[SocketAddr], wait: Duration) -> Result<Vec<(IpAddr, u16)>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+    let xid = std::process::id() << 16 | 0x1000;
+    let args = rpc::getport_args(vxi11::DEVICE_CORE_PROG, vxi11::DEVICE_CORE_VERS, rpc::IPPROTO_TCP);
+    let call = rpc::call_message(xid, rpc::PORTMAP_PROG, rpc::PORTMAP_VERS, rpc::PMAPPROC_GETPORT, &args);
+    for target in targets {
+        socket.send_to(&call, target)?;
+    }
+
+    let mut responders = Vec::new();
+    let deadline = Instant::now() + wait;
+    let mut buf = [0u8; 1500];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => break,
+            Err(err) => return Err(err.into()),
+        };
+        let port = match rpc::parse_reply(xid, &buf[..len]).and_then(|results| XdrReader::new(results).u32()) {
+            Ok(port) => port,
+            Err(_) => continue,
+        };
+        if let Ok(port @ 1..) = u16::try_from(port) {
+            if !responders.contains(&(from.ip(), port)) {
+                responders.push((from.ip(), port));
+            }
+        }
+    }
+    Ok(responders)
+}
+
+fn identify(address: IpAddr, port: u16, timeouts: Timeouts) -> Result<Identity> {
+    let stream = resource::connect(&address.to_string(), port, timeouts)?;
+    let mut device = Vxi11::create_link(stream, "inst0", timeouts)?;
+    device.write_scip_cmd(b"*IDN?\n")?;
+    Identity::parse(&device.read_result()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::XdrWriter;
+    use crate::simulator::Instrument;
+    use crate::vxi11::stand_in;
+
+    // Answers GETPORT datagrams with `port`.
+    fn portmapper(port: u32) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 1500];
+            while let Ok((len, from)) = socket.recv_from(&mut buf) {
+                let call = rpc::parse_call(&buf[..len]).unwrap();
+                let mut results = XdrWriter::new();
+                results.u32(port);
+                socket.send_to(&rpc::reply_message(call.xid, results.bytes()), from).unwrap();
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn test_discover_stand_in() {
+        let scope = portmapper(stand_in::spawn(Instrument::default()) as u32);
+        let generator = portmapper(stand_in::spawn(Instrument { idn: "RIGOL TECHNOLOGIES,DG1022Z,DG1ZA0001,00.03".to_string(), ..Instrument::default() }) as u32);
+        let no_vxi11 = portmapper(0);
+
+        let found = discover_on(&[scope, generator, no_vxi11], Duration::from_millis(300), Timeouts::default()).unwrap();
+        assert_eq!(found.len(), 1);
+        let instrument = &found[0];
+        assert_eq!(instrument.identity.model, "DS1104Z");
+        assert_eq!(instrument.identity.serial, "DS1ZA000000001");
+        assert_eq!(instrument.identity.firmware, "00.04.04.SP3");
+        assert_eq!(instrument.resource().to_string(), "TCPIP0::127.0.0.1::inst0::INSTR");
+        assert_eq!(instrument.socket_resource().to_string(), "TCPIP0::127.0.0.1::5555::SOCKET");
+    }
+}
@@ -0,0 +1,154 @@
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use crate::block::BlockError;
+use crate::command::WAVeformCommand::{MaxMemorySize, MaxTransferSize, Mode};
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+// An error reported by the instrument itself through its SCPI error queue,
+// e.g. `-113,"Undefined header"`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct InstrumentError {
+    pub code: i32,
+    pub message: String,
+    pub command: Option<String>,
+}
+
+impl InstrumentError {
+    // Parses one `:SYSTem:ERRor?` entry. `0,"No error"` gives `None`.
+    pub fn parse(response: &str, command: Option<&str>) -> Result<Option<InstrumentError>> {
+        let (code, message) = response.trim().split_once(',').ok_or_else(|| Error::parse_error(response, "error queue entry"))?;
+        let code: i32 = code.trim().parse().map_err(|_| Error::parse_error(response, "error queue entry"))?;
+        if code == 0 {
+            return Ok(None);
+        }
+        let message = message.trim();
+        let message = message.strip_prefix('"').and_then(|m| m.strip_suffix('"')).unwrap_or(message);
+        Ok(Some(InstrumentError { code, message: message.to_string(), command: command.map(str::to_string) }))
+    }
+}
+
+impl fmt::Display for InstrumentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},\"{}\"", self.code, self.message)?;
+        if let Some(command) = &self.command {
+            write!(f, " after {:?}", command)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    IoError(io::Error),
+    Timeout(io::Error),
+    ProtocolError(String),
+    BlockError(BlockError),
+    ParseError { response: String, expected: &'static str },
+    NotAvailable,
+    InstrumentError(InstrumentError),
+    // several entries, oldest first
+    InstrumentErrors(Vec<InstrumentError>),
+    InvalidArgument(String),
+    VerificationError { header: String, written: String, read: String },
+    Unsupported { feature: String, model: String },
+    Cancelled,
+    CanNotChangeMode(Mode),
+    ExceededMaxMemorySize(MaxMemorySize),
+    StartIsGreaterThanStop(u32, u32),
+    ExceedeMaxTransferSize(MaxTransferSize),
+}
+
+impl Error {
+    pub fn parse_error(response: &str, expected: &'static str) -> Error {
+        Error::ParseError { response: response.to_string(), expected }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::IoError(err) => write!(f, "IO error: {}", err),
+            Error::Timeout(err) => write!(f, "Timed out waiting for the instrument: {}", err),
+            Error::ProtocolError(msg) => write!(f, "Protocol error: {}", msg),
+            Error::BlockError(err) => write!(f, "Block transfer error: {}", err),
+            Error::ParseError { response, expected } => write!(f, "Can not convert the response {:?} to {}", response, expected),
+            Error::NotAvailable => write!(f, "The instrument has no valid value (9.9E37)"),
+            Error::InstrumentError(err) => write!(f, "Instrument reported an error: {}", err),
+            Error::InstrumentErrors(errors) => {
+                let errors: Vec<String> = errors.iter().map(InstrumentError::to_string).collect();
+                write!(f, "Instrument reported {} errors: {}", errors.len(), errors.join("; "))
+            }
+            Error::InvalidArgument(msg) => write!(f, "Invalid argument: {}", msg),
+            Error::VerificationError { header, written, read } => write!(f, "{} was set to {} but reads back {}", header, written, read),
+            Error::Unsupported { feature, model } => write!(f, "{} is not supported by {}", feature, model),
+            Error::Cancelled => write!(f, "The operation was cancelled"),
+            Error::CanNotChangeMode(err) => write!(f, "The mode MAX and RAW has to set the triger mode to SINGLE: {}", err),
+            Error::ExceededMaxMemorySize(memory_size) => write!(f, "Exceeded the max memory size {:?}", memory_size),
+            Error::StartIsGreaterThanStop(start, stop) => write!(f, "the start point {} is greater than the stop point {}", start, stop),
+            Error::ExceedeMaxTransferSize(transfer_size) => write!(f, "Exceeded the max transfer size {:?}", transfer_size),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IoError(err) => Some(err),
+            Error::Timeout(err) => Some(err),
+            Error::BlockError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Error::Timeout(err),
+            _ => Error::IoError(err),
+        }
+    }
+}
+
+impl From<BlockError> for Error {
+    fn from(err: BlockError) -> Self {
+        match err {
+            BlockError::IoError(err) => err.into(),
+            err => Error::BlockError(err),
+        }
+    }
+}
+
+impl From<Mode> for Error {
+    fn from(err: Mode) -> Self {
+        Error::CanNotChangeMode(err)
+    }
+}
+
+// Parses a query response, keeping the offending text on failure.
+pub(crate) fn parse_response<T: FromStr>(response: &str) -> Result<T> {
+    response.trim().parse().map_err(|_| Error::parse_error(response, std::any::type_name::<T>()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_mapping() {
+        assert!(matches!(Error::from(io::Error::from(io::ErrorKind::WouldBlock)), Error::Timeout(_)));
+        assert!(matches!(Error::from(io::Error::from(io::ErrorKind::ConnectionReset)), Error::IoError(_)));
+        assert!(matches!(Error::from(BlockError::Truncated { expected: 2, received: 1 }), Error::BlockError(_)));
+        let entry = InstrumentError::parse("-113,\"Undefined header\"\n", Some(":WAV:BOGUS 1")).unwrap().unwrap();
+        assert_eq!((entry.code, entry.message.as_str()), (-113, "Undefined header"));
+        assert_eq!(entry.to_string(), "-113,\"Undefined header\" after \":WAV:BOGUS 1\"");
+        assert_eq!(InstrumentError::parse("0,\"No error\"", None).unwrap(), None);
+        assert!(matches!(InstrumentError::parse("garbage", None), Err(Error::ParseError { .. })));
+        match parse_response::<f32>("1.0e-3x") {
+            Err(Error::ParseError { response, expected: "f32" }) => assert_eq!(response, "1.0e-3x"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,276 @@
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::Duration;
+
+// A byte stream decorator that misbehaves on purpose. Faults are scheduled at byte
+// offsets of the incoming stream, counted from the moment they are injected. Wrap the
+// injector in `BufStream` to use it as a `Visa`:
+//
+//     let device = BufStream::new(FaultInjector::new(Simulator::new()));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Fault {
+    Delay(Duration),
+    Drop(usize),
+    Timeout,
+    Eof,
+}
+
+#[derive(Debug)]
+pub struct FaultInjector<T> {
+    inner: T,
+    position: u64,
+    scheduled: Vec<(u64, Fault)>,
+    max_read: Option<usize>,
+    delay: Option<Duration>,
+    eof: bool,
+}
+
+impl<T> FaultInjector<T> {
+    pub fn new(inner: T) -> FaultInjector<T> {
+        FaultInjector {
+            inner,
+            position: 0,
+            scheduled: Vec::new(),
+            max_read: None,
+            delay: None,
+            eof: false,
+        }
+    }
+
+    // Every read returns at most `max_read` bytes.
+    pub fn set_max_read(&mut self, max_read: Option<usize>) {
+        self.max_read = max_read.map(|n| n.max(1));
+    }
+
+    // Every read is delayed by `delay`.
+    pub fn set_delay(&mut self, delay: Option<Duration>) {
+        self.delay = delay;
+    }
+
+    pub fn inject(&mut self, after: u64, fault: Fault) {
+        self.scheduled.push((self.position + after, fault));
+        self.scheduled.sort_by_key(|(at, _)| *at);
+    }
+
+    pub fn clear(&mut self) {
+        self.scheduled.clear();
+        self.max_read = None;
+        self.delay = None;
+        self.eof = false;
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Read> FaultInjector<T> {
+    fn discard(&mut self, mut len: usize) -> io::Result<()> {
+        let mut scratch = [0u8; 4096];
+        while len > 0 {
+            let n = self.inner.read(&mut scratch[..len.min(4096)])?;
+            if n == 0 {
+                break;
+            }
+            self.position += n as u64;
+            len -= n;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Read> Read for FaultInjector<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(delay) = self.delay {
+            thread::sleep(delay);
+        }
+        while let Some(&(at, fault)) = self.scheduled.first() {
+            if at > self.position {
+                break;
+            }
+            self.scheduled.remove(0);
+            match fault {
+                Fault::Delay(delay) => thread::sleep(delay),
+                Fault::Drop(len) => self.discard(len)?,
+                Fault::Timeout => return Err(io::Error::new(io::ErrorKind::TimedOut, "injected timeout")),
+                Fault::Eof => self.eof = true,
+            }
+        }
+        if self.eof || buf.is_empty() {
+            return Ok(0);
+        }
+        let mut limit = buf.len().min(self.max_read.unwrap_or(usize::MAX));
+        if let Some(&(at, _)) = self.scheduled.first() {
+            limit = limit.min((at - self.position) as usize);
+        }
+        let n = self.inner.read(&mut buf[..limit])?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for FaultInjector<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::BlockError;
+    use crate::command::TRIGgerCommand::SWEep;
+    use crate::command::WAVeformCommand::{get_data, ConvertData, Format, Mode};
+    use crate::device::{BufStream, Visa};
+    use crate::error::{Error, Result};
+    use crate::simulator::{Instrument, Simulator};
+    use crate::{Ds1000z, Recovery};
+
+    const RANGE: u32 = 300000;
+
+    type Scope = Ds1000z<BufStream<FaultInjector<Simulator>>>;
+
+    fn scope() -> Scope {
+        let simulator = Simulator::with_instrument(Instrument { memory_depth: Some(RANGE), ..Instrument::default() });
+        let device = BufStream::new(FaultInjector::new(simulator));
+        let mut scope = Ds1000z::new(device).unwrap();
+        scope.trigger().sweep(SWEep::SING).unwrap();
+        let mut waveform = scope.waveform();
+        waveform.format(Format::BYTE).unwrap();
+        waveform.mode(Mode::RAW).unwrap();
+        scope
+    }
+
+    fn injector(scope: &mut Scope) -> &mut FaultInjector<Simulator> {
+        scope.device().get_mut()
+    }
+
+    // Length of the ":WAV:PREamble?" reply that starts every chunk.
+    fn preamble_len(scope: &mut Scope) -> u64 {
+        scope.query(":WAV:PRE?").unwrap().len() as u64 + 1
+    }
+
+    fn acquire(scope: &mut Scope, convert_data: &mut ConvertData) -> Result<()> {
+        get_data(RANGE, &mut scope.waveform(), convert_data)
+    }
+
+    #[test]
+    fn test_short_reads_and_delays_recover() {
+        let mut clean = ConvertData::new();
+        acquire(&mut scope(), &mut clean).unwrap();
+
+        let mut scope = scope();
+        injector(&mut scope).set_max_read(Some(7));
+        injector(&mut scope).inject(1000, Fault::Delay(Duration::from_millis(20)));
+        let mut convert_data = ConvertData::new();
+        acquire(&mut scope, &mut convert_data).unwrap();
+        assert_eq!(convert_data.count, RANGE);
+        assert_eq!(convert_data.data[..RANGE as usize], clean.data[..RANGE as usize]);
+    }
+
+    #[test]
+    fn test_premature_eof_is_reported() {
+        let mut scope = scope();
+        injector(&mut scope).inject(260000, Fault::Eof);
+        let mut convert_data = ConvertData::new();
+        match acquire(&mut scope, &mut convert_data) {
+            Err(Error::BlockError(BlockError::Truncated { expected: 50000, .. })) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(convert_data.count, 250000);
+    }
+
+    #[test]
+    fn test_dropped_bytes_time_out() {
+        let mut scope = scope();
+        scope.set_recovery(Recovery::Off);
+        injector(&mut scope).inject(1000, Fault::Drop(10));
+        let mut convert_data = ConvertData::new();
+        assert!(matches!(acquire(&mut scope, &mut convert_data), Err(Error::Timeout(_))));
+        assert_eq!(convert_data.count, 0);
+    }
+
+    #[test]
+    fn test_missing_terminator_times_out() {
+        let mut scope = scope();
+        scope.set_recovery(Recovery::Off);
+        // the preamble, ":WAV:STAR?" and ":WAV:STOP?" answer "1\n" and "250000\n", then
+        // the block header "#9000250000" and the payload precede the terminator
+        let terminator = preamble_len(&mut scope) + 2 + 7 + 11 + 250000;
+        injector(&mut scope).inject(terminator, Fault::Drop(1));
+        let mut convert_data = ConvertData::new();
+        assert!(matches!(acquire(&mut scope, &mut convert_data), Err(Error::Timeout(_))));
+        assert_eq!(convert_data.count, 0);
+    }
+
+    #[test]
+    fn test_timeout_on_query() {
+        let mut scope = scope();
+        scope.set_recovery(Recovery::Off);
+        injector(&mut scope).inject(0, Fault::Timeout);
+        assert!(matches!(scope.waveform().get_start_point(), Err(Error::Timeout(_))));
+    }
+
+    #[test]
+    fn test_error_queue_is_not_retried() {
+        let mut scope = scope();
+        scope.write(":WAV:BOGus 1").unwrap();
+        injector(&mut scope).inject(0, Fault::Timeout);
+        assert!(matches!(scope.drain_errors(), Err(Error::Timeout(_))));
+        // the entry is still on its way rather than swallowed by a resync
+        assert_eq!(scope.device().read_result().unwrap(), "-113,\"Undefined header\"");
+        assert!(scope.drain_errors().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_batch_with_commands_is_not_retried() {
+        let mut scope = scope();
+        injector(&mut scope).inject(0, Fault::Timeout);
+        let mut batch = scope.batch();
+        batch.command(":WAV:BOGus 1");
+        batch.query::<u32>(":WAV:STAR?");
+        assert!(matches!(batch.send(), Err(Error::Timeout(_))));
+        assert_eq!(scope.device().read_result().unwrap(), "1");
+        let codes: Vec<i32> = scope.drain_errors().unwrap().iter().map(|err| err.code).collect();
+        assert_eq!(codes, [-113]);
+    }
+
+    #[test]
+    fn test_resync_after_timeouts() {
+        let mut clean = ConvertData::new();
+        acquire(&mut scope(), &mut clean).unwrap();
+
+        let terminator = preamble_len(&mut scope()) + 2 + 7 + 11 + 250000;
+        for (after, fault) in [(1000, Fault::Drop(10)), (terminator, Fault::Drop(1)), (0, Fault::Timeout)] {
+            let mut scope = scope();
+            scope.write("*ESE 36").unwrap();
+            scope.write(":WAV:BOGus 1").unwrap();
+            injector(&mut scope).inject(after, fault);
+            let mut convert_data = ConvertData::new();
+            acquire(&mut scope, &mut convert_data).unwrap();
+            assert_eq!(convert_data.data[..RANGE as usize], clean.data[..RANGE as usize], "{:?}", fault);
+            // nothing stale is left behind, and the status state is as it was
+            assert_eq!(scope.query(":WAV:STAR?").unwrap(), "250001");
+            assert_eq!(injector(&mut scope).get_ref().instrument().event_status_enable, 36);
+            let codes: Vec<i32> = scope.drain_errors().unwrap().iter().map(|err| err.code).collect();
+            assert_eq!(codes, [-113]);
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use std::fmt;
+use std::str::FromStr;
+use crate::error::{Error, Result};
+use crate::firmware::FirmwareVersion;
+use crate::model::{self, Capabilities};
+
+// The four fields of an `*IDN?` response, e.g.
+// `RIGOL TECHNOLOGIES,DS1104Z,DS1ZA000000001,00.04.04.SP3`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Identity {
+    pub manufacturer: String,
+    pub model: String,
+    pub serial: String,
+    pub firmware: String,
+}
+
+impl Identity {
+    pub fn parse(response: &str) -> Result<Identity> {
+        let fields: Vec<&str> = response.trim().splitn(4, ',').map(str::trim).collect();
+        match fields[..] {
+            [manufacturer, model, serial, firmware] if !manufacturer.is_empty() && !model.is_empty() => Ok(Identity {
+                manufacturer: manufacturer.to_string(),
+                model: model.to_string(),
+                serial: serial.to_string(),
+                firmware: firmware.to_string(),
+            }),
+            _ => Err(Error::parse_error(response, "*IDN? response")),
+        }
+    }
+
+    pub fn capabilities(&self) -> Option<&'static Capabilities> {
+        model::lookup(&self.model)
+    }
+
+    pub fn firmware_version(&self) -> Result<FirmwareVersion> {
+        self.firmware.parse()
+    }
+
+    // DS1054Z, DS1104Z-S Plus, DS1202Z-E, MSO1104Z, ...
+    pub fn is_ds1000z(&self) -> bool {
+        let model = self.model.to_ascii_uppercase();
+        self.manufacturer.to_ascii_uppercase().starts_with("RIGOL")
+            && (model.starts_with("DS1") || model.starts_with("MSO1"))
+            && model.trim_start_matches(|c: char| c.is_ascii_alphabetic()).trim_start_matches(|c: char| c.is_ascii_digit()).starts_with('Z')
+    }
+}
+
+impl FromStr for Identity {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Identity::parse(s)
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{},{}", self.manufacturer, self.model, self.serial, self.firmware)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_idn() {
+        let identity = Identity::parse("RIGOL TECHNOLOGIES,DS1104Z,DS1ZA000000001,00.04.04.SP3\n").unwrap();
+        assert_eq!(identity.model, "DS1104Z");
+        assert_eq!(identity.serial, "DS1ZA000000001");
+        assert_eq!(identity.firmware, "00.04.04.SP3");
+        assert_eq!(identity.firmware_version().unwrap(), FirmwareVersion::new(&[0, 4, 4], Some(3)));
+        assert_eq!(identity.to_string(), "RIGOL TECHNOLOGIES,DS1104Z,DS1ZA000000001,00.04.04.SP3");
+        assert!(identity.is_ds1000z());
+        for model in ["DS1202Z-E", "MSO1104Z", "DS1104Z-S Plus"] {
+            assert!(Identity { model: model.to_string(), ..identity.clone() }.is_ds1000z(), "{}", model);
+        }
+        for model in ["DS2202A", "DS1102E", "DG1022Z"] {
+            assert!(!Identity { model: model.to_string(), ..identity.clone() }.is_ds1000z(), "{}", model);
+        }
+        assert!(matches!(Identity::parse("RIGOL,DS1104Z"), Err(Error::ParseError { .. })));
+    }
+}
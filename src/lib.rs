@@ -1,2 +1,26 @@
+#[cfg(feature = "tokio")]
+pub mod asynchronous;
+pub mod batch;
+pub mod block;
+pub mod cache;
 pub mod command;
-pub mod device;
\ No newline at end of file
+pub mod device;
+pub mod discovery;
+pub mod error;
+pub mod fault;
+pub mod firmware;
+pub mod identity;
+pub mod model;
+pub mod number;
+pub mod property;
+pub mod resource;
+pub mod rpc;
+pub mod scpi;
+pub mod session;
+pub mod simulator;
+pub mod transcript;
+pub mod usbtmc;
+pub mod vxi11;
+
+pub use error::{Error, Result};
+pub use session::{Ds1000z, ErrorChecking, Recovery};
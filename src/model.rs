@@ -0,0 +1,78 @@
+// What each DS1000Z-family model can do, looked up by the model field of `*IDN?`.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Capabilities {
+    // as reported by `*IDN?`
+    pub model: &'static str,
+    pub analog_channels: u8,
+    // the Plus models take the 16 channel logic analyzer as an upgrade; they report 0
+    // here until it is installed
+    pub digital_channels: u8,
+    // Hz
+    pub bandwidth: f64,
+    // Sa/s with a single channel enabled
+    pub max_sample_rate: f64,
+    // the `:ACQuire:MDEPth` settings with a single channel enabled; the depths halve with
+    // two channels and halve again with three or four
+    pub memory_depths: &'static [u32],
+    // the 2 channel 25 MHz arbitrary waveform generator of the -S models
+    pub generator: bool,
+}
+
+// The 24 Mpts depth is standard on the Plus, -E and MSO models and an option on the others.
+const MEMORY_DEPTHS: &[u32] = &[12_000, 120_000, 1_200_000, 12_000_000, 24_000_000];
+
+const fn model(model: &'static str, analog_channels: u8, digital_channels: u8, bandwidth: f64, generator: bool) -> Capabilities {
+    Capabilities { model, analog_channels, digital_channels, bandwidth, max_sample_rate: 1e9, memory_depths: MEMORY_DEPTHS, generator }
+}
+
+pub const MODELS: &[Capabilities] = &[
+    model("DS1054Z", 4, 0, 50e6, false),
+    model("DS1074Z", 4, 0, 70e6, false),
+    model("DS1104Z", 4, 0, 100e6, false),
+    model("DS1074Z-S", 4, 0, 70e6, true),
+    model("DS1104Z-S", 4, 0, 100e6, true),
+    model("DS1074Z Plus", 4, 0, 70e6, false),
+    model("DS1104Z Plus", 4, 0, 100e6, false),
+    model("DS1074Z-S Plus", 4, 0, 70e6, true),
+    model("DS1104Z-S Plus", 4, 0, 100e6, true),
+    model("DS1102Z-E", 2, 0, 100e6, false),
+    model("DS1202Z-E", 2, 0, 200e6, false),
+    model("MSO1074Z", 4, 16, 70e6, false),
+    model("MSO1104Z", 4, 16, 100e6, false),
+    model("MSO1074Z-S", 4, 16, 70e6, true),
+    model("MSO1104Z-S", 4, 16, 100e6, true),
+];
+
+// Ignores case and spaces, so `DS1104Z-S PLUS` and `DS1104Z-SPlus` find `DS1104Z-S Plus`.
+pub(crate) fn normalize(model: &str) -> String {
+    model.chars().filter(|c| !c.is_whitespace()).map(|c| c.to_ascii_uppercase()).collect()
+}
+
+pub fn lookup(model: &str) -> Option<&'static Capabilities> {
+    let model = normalize(model);
+    MODELS.iter().find(|capabilities| normalize(capabilities.model) == model)
+}
+
+impl Capabilities {
+    pub fn max_memory_depth(&self) -> u32 {
+        self.memory_depths.iter().copied().max().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let ds1054z = lookup("DS1054Z").unwrap();
+        assert_eq!((ds1054z.analog_channels, ds1054z.digital_channels, ds1054z.bandwidth, ds1054z.generator), (4, 0, 50e6, false));
+        assert_eq!(ds1054z.max_memory_depth(), 24_000_000);
+        assert_eq!(lookup("ds1104z-s  PLUS").unwrap().model, "DS1104Z-S Plus");
+        assert!(lookup("DS1104Z-S Plus").unwrap().generator);
+        assert_eq!(lookup("DS1202Z-E").unwrap().analog_channels, 2);
+        assert_eq!(lookup("MSO1104Z").unwrap().digital_channels, 16);
+        assert_eq!(lookup("DS2202A"), None);
+    }
+}
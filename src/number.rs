@@ -0,0 +1,126 @@
+use std::fmt;
+use crate::error::{Error, Result};
+
+// SCPI numbers. Responses come as NR1 (`100`), NR2 (`-0.25`) or NR3 (`1.000000e-03`);
+// a value the scope could not determine, such as a measurement without a signal, is
+// reported as 9.9E37.
+
+pub const NOT_AVAILABLE: f64 = 9.9e37;
+
+fn is_numeric(s: &str) -> bool {
+    let s = s.strip_prefix(['+', '-']).unwrap_or(s);
+    let (mantissa, exponent) = match s.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, Some(exponent.strip_prefix(['+', '-']).unwrap_or(exponent))),
+        None => (s, None),
+    };
+    let (whole, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+    !(whole.is_empty() && fraction.is_empty())
+        && digits(whole)
+        && digits(fraction)
+        && exponent.is_none_or(|exponent| !exponent.is_empty() && digits(exponent))
+}
+
+pub fn is_not_available(value: f64) -> bool {
+    value.abs() >= NOT_AVAILABLE * (1.0 - 1e-6)
+}
+
+// Parses an NR1/NR2/NR3 response, `None` for the not-available value.
+pub fn parse_optional(response: &str) -> Result<Option<f64>> {
+    let text = response.trim();
+    if !is_numeric(text) {
+        return Err(Error::parse_error(response, "number"));
+    }
+    let value: f64 = text.parse().map_err(|_| Error::parse_error(response, "number"))?;
+    Ok(if is_not_available(value) { None } else { Some(value) })
+}
+
+// Like `parse_optional`, with the not-available value as `Error::NotAvailable`.
+pub fn parse_number(response: &str) -> Result<f64> {
+    parse_optional(response)?.ok_or(Error::NotAvailable)
+}
+
+// SCPI suffix multipliers. They are case insensitive, so `M` is milli and mega is `MA`.
+const MULTIPLIERS: [(&str, f64); 11] = [
+    ("EX", 1e18), ("PE", 1e15), ("T", 1e12), ("G", 1e9), ("MA", 1e6), ("K", 1e3),
+    ("M", 1e-3), ("U", 1e-6), ("N", 1e-9), ("P", 1e-12), ("F", 1e-15),
+];
+
+// Parses a program argument such as `500u` or `2.5E-3`.
+pub fn parse_argument(argument: &str) -> Result<f64> {
+    let text = argument.trim();
+    let split = text.len() - text.trim_end_matches(|c: char| c.is_ascii_alphabetic()).len();
+    let (number, suffix) = text.split_at(text.len() - split);
+    let multiplier = match MULTIPLIERS.iter().find(|(name, _)| suffix.eq_ignore_ascii_case(name)) {
+        Some(&(_, multiplier)) => multiplier,
+        None if suffix.is_empty() => 1.0,
+        None => return Err(Error::parse_error(argument, "number")),
+    };
+    if !is_numeric(number) {
+        return Err(Error::parse_error(argument, "number"));
+    }
+    let value: f64 = number.parse().map_err(|_| Error::parse_error(argument, "number"))?;
+    Ok(value * multiplier)
+}
+
+// Formats a setter argument with an SI suffix multiplier, e.g. `Si(0.0005)` as `500u`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Si(pub f64);
+
+const PREFIXES: [(&str, f64); 8] = [
+    ("G", 1e9), ("MA", 1e6), ("k", 1e3), ("", 1.0), ("m", 1e-3), ("u", 1e-6), ("n", 1e-9), ("p", 1e-12),
+];
+
+impl fmt::Display for Si {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.0.is_finite() || self.0 == 0.0 {
+            return write!(f, "{}", self.0);
+        }
+        // rounded to the 7 significant digits the scope keeps, first, so that 999.99999e-6
+        // becomes 1m, not 1000u
+        let value: f64 = format!("{:.6e}", self.0).parse().unwrap_or(self.0);
+        let (prefix, scale) = PREFIXES.iter().copied().find(|&(_, scale)| value.abs() >= scale).unwrap_or(PREFIXES[PREFIXES.len() - 1]);
+        let mantissa = value / scale;
+        let decimals = (6 - mantissa.abs().log10().floor() as i32).max(0) as usize;
+        let text = format!("{:.*}", decimals, mantissa);
+        let text = if text.contains('.') { text.trim_end_matches('0').trim_end_matches('.') } else { &text };
+        write!(f, "{}{}", text, prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number("100\n").unwrap(), 100.0);
+        assert_eq!(parse_number(" -0.25 ").unwrap(), -0.25);
+        assert_eq!(parse_number("1.000000e-03").unwrap(), 1e-3);
+        assert_eq!(parse_number("+4.0E+2").unwrap(), 400.0);
+        assert_eq!(parse_optional("9.9E37\n").unwrap(), None);
+        assert_eq!(parse_optional("9.900000e+37").unwrap(), None);
+        assert!(matches!(parse_number("9.9E37"), Err(Error::NotAvailable)));
+        for bad in ["", "inf", "NaN", "1.0e", "1,5", "e3", "0x10"] {
+            assert!(matches!(parse_number(bad), Err(Error::ParseError { .. })), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_si() {
+        assert_eq!(Si(1e-3).to_string(), "1m");
+        assert_eq!(Si(0.0005).to_string(), "500u");
+        assert_eq!(Si(2.0).to_string(), "2");
+        assert_eq!(Si(-1500.0).to_string(), "-1.5k");
+        assert_eq!(Si(0.0).to_string(), "0");
+        assert_eq!(Si(999.999999e-6).to_string(), "1m");
+        assert_eq!(Si(12e6).to_string(), "12MA");
+        assert_eq!(Si(3.3e-9).to_string(), "3.3n");
+        for value in [1e-3, 0.0005, 2.0, -1500.0, 12e6, 3.3e-9, 0.123456] {
+            let parsed = parse_argument(&Si(value).to_string()).unwrap();
+            assert!((parsed - value).abs() <= value.abs() * 1e-12, "{} != {}", parsed, value);
+        }
+        assert_eq!(parse_argument("2.5E-3").unwrap(), 2.5e-3);
+        assert!(parse_argument("5 volts").is_err());
+    }
+}
@@ -0,0 +1,215 @@
+use std::fmt;
+use crate::device::Visa;
+use crate::error::{parse_response, Error, Result};
+use crate::firmware::{self, Quirk};
+use crate::number::{parse_number, Si};
+use crate::session::Ds1000z;
+
+// One instrument setting: its SCPI header, the valid range and how far a read back value
+// may be from the written one. Subsystems declare their settings as constants,
+//
+//     const SCALE: Property<f64> = Property::new(":TIMebase:SCALe").range(5e-9, 50.0).tolerance(1e-4);
+//
+// and every setting is then read and written the same way:
+//
+//     SCALE.set(&mut scope, 1e-3)?;
+//     let scale = SCALE.get(&mut scope)?;
+//     SCALE.set_verified(&mut scope, 2e-3)?;
+//     let known = SCALE.cached(&scope);
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Property<T> {
+    header: &'static str,
+    min: Option<T>,
+    max: Option<T>,
+    tolerance: Option<f64>,
+}
+
+// A type a setting can hold.
+pub trait PropertyValue: Copy + PartialOrd + fmt::Debug {
+    // The tolerance of a property that does not set one.
+    const TOLERANCE: f64 = 0.0;
+
+    fn parse(response: &str) -> Result<Self>;
+
+    // `parse` for a scope with `quirks`.
+    fn parse_with(response: &str, _quirks: &[Quirk]) -> Result<Self> {
+        Self::parse(response)
+    }
+
+    // The program argument for `self`.
+    fn argument(&self) -> String;
+
+    // Whether `self`, read back after writing `written`, is the same setting.
+    fn matches(&self, written: &Self, _tolerance: f64) -> bool {
+        self == written
+    }
+}
+
+impl PropertyValue for u32 {
+    fn parse(response: &str) -> Result<u32> {
+        parse_response(response)
+    }
+
+    fn parse_with(response: &str, quirks: &[Quirk]) -> Result<u32> {
+        firmware::parse_u32(response, quirks)
+    }
+
+    fn argument(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl PropertyValue for i32 {
+    fn parse(response: &str) -> Result<i32> {
+        parse_response(response)
+    }
+
+    fn argument(&self) -> String {
+        self.to_string()
+    }
+}
+
+// `tolerance` is relative to the written value. The default one allows for the 7
+// significant digits of the scope's replies.
+impl PropertyValue for f64 {
+    const TOLERANCE: f64 = 1e-6;
+
+    fn parse(response: &str) -> Result<f64> {
+        parse_number(response)
+    }
+
+    fn argument(&self) -> String {
+        Si(*self).to_string()
+    }
+
+    fn matches(&self, written: &f64, tolerance: f64) -> bool {
+        (self - written).abs() <= tolerance * written.abs()
+    }
+}
+
+impl PropertyValue for f32 {
+    const TOLERANCE: f64 = f64::TOLERANCE;
+
+    fn parse(response: &str) -> Result<f32> {
+        Ok(parse_number(response)? as f32)
+    }
+
+    fn argument(&self) -> String {
+        Si(*self as f64).to_string()
+    }
+
+    fn matches(&self, written: &f32, tolerance: f64) -> bool {
+        (*self as f64).matches(&(*written as f64), tolerance)
+    }
+}
+
+impl PropertyValue for bool {
+    fn parse(response: &str) -> Result<bool> {
+        match response.trim() {
+            "1" | "ON" => Ok(true),
+            "0" | "OFF" => Ok(false),
+            _ => Err(Error::parse_error(response, "bool")),
+        }
+    }
+
+    fn argument(&self) -> String {
+        if *self { "1" } else { "0" }.to_string()
+    }
+}
+
+impl<T: Copy> Property<T> {
+    pub const fn new(header: &'static str) -> Property<T> {
+        Property { header, min: None, max: None, tolerance: None }
+    }
+
+    pub const fn range(self, min: T, max: T) -> Property<T> {
+        Property { min: Some(min), max: Some(max), ..self }
+    }
+
+    pub const fn tolerance(self, tolerance: f64) -> Property<T> {
+        Property { tolerance: Some(tolerance), ..self }
+    }
+
+    pub fn header(&self) -> &'static str {
+        self.header
+    }
+}
+
+impl<T: PropertyValue> Property<T> {
+    pub fn check(&self, value: T) -> Result<()> {
+        let below = self.min.is_some_and(|min| value < min);
+        let above = self.max.is_some_and(|max| value > max);
+        if below || above {
+            return Err(Error::InvalidArgument(format!("{:?} is outside {:?}..={:?} for {}", value, self.min, self.max, self.header)));
+        }
+        Ok(())
+    }
+
+    pub fn get<V: Visa>(&self, scope: &mut Ds1000z<V>) -> Result<T> {
+        let response = scope.query(&format!("{}?", self.header))?;
+        let value = T::parse_with(&response, scope.quirks())?;
+        scope.cache.store(self.header, &response);
+        Ok(value)
+    }
+
+    // Checks the range and writes `value`. The cached value is dropped until it is read
+    // back, as the scope may round or refuse it.
+    pub fn set<V: Visa>(&self, scope: &mut Ds1000z<V>, value: T) -> Result<()> {
+        self.check(value)?;
+        scope.write(&format!("{} {}", self.header, value.argument()))
+    }
+
+    // Writes `value` and reads it back, failing when the scope did not take it.
+    pub fn set_verified<V: Visa>(&self, scope: &mut Ds1000z<V>, value: T) -> Result<T> {
+        self.set(scope, value)?;
+        let read = self.get(scope)?;
+        if !read.matches(&value, self.tolerance.unwrap_or(T::TOLERANCE)) {
+            return Err(Error::VerificationError { header: self.header.to_string(), written: value.argument(), read: read.argument() });
+        }
+        Ok(read)
+    }
+
+    // The value last read from the scope, if any.
+    pub fn cached<V: Visa>(&self, scope: &Ds1000z<V>) -> Option<T> {
+        scope.cache.get(self.header).and_then(|text| T::parse_with(text, scope.quirks()).ok())
+    }
+
+    // The cached value, or the one read from the scope when there is none.
+    pub fn current<V: Visa>(&self, scope: &mut Ds1000z<V>) -> Result<T> {
+        match self.cached(scope) {
+            Some(value) => Ok(value),
+            None => self.get(scope),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::Simulator;
+
+    const SCALE: Property<f64> = Property::new(":TIMebase:SCALe").range(5e-9, 50.0);
+
+    #[test]
+    fn test_property() {
+        let mut scope = Ds1000z::new(Simulator::new()).unwrap();
+        assert_eq!(SCALE.cached(&scope), None);
+        assert_eq!(SCALE.get(&mut scope).unwrap(), 1e-3);
+        assert_eq!(SCALE.cached(&scope), Some(1e-3));
+
+        SCALE.set(&mut scope, 2e-3).unwrap();
+        assert_eq!(SCALE.cached(&scope), None);
+        assert_eq!(SCALE.get(&mut scope).unwrap(), 2e-3);
+        assert!(matches!(SCALE.set(&mut scope, 100.0), Err(Error::InvalidArgument(_))));
+        assert_eq!(SCALE.get(&mut scope).unwrap(), 2e-3);
+
+        // the scope answers with 7 significant digits
+        match SCALE.tolerance(0.0).set_verified(&mut scope, 1.23456789e-3) {
+            Err(Error::VerificationError { header, read, .. }) => assert_eq!((header.as_str(), read.as_str()), (":TIMebase:SCALe", "1.234568m")),
+            other => panic!("unexpected {:?}", other),
+        }
+        let scale = SCALE.set_verified(&mut scope, 1.23456789e-3).unwrap();
+        assert_eq!(scale, 1.234568e-3);
+        assert_eq!(SCALE.cached(&scope), Some(1.234568e-3));
+    }
+}
@@ -0,0 +1,305 @@
+use std::fmt;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use crate::device::{BufStream, Visa};
+use crate::error::{Error, Result};
+use crate::simulator::Simulator;
+use crate::transcript::Replay;
+use crate::vxi11::Vxi11;
+
+// VISA style resource strings:
+//
+//     TCPIP[board]::<host>::<port>::SOCKET      raw SCPI socket (port 5555 on a DS1000Z)
+//     TCPIP[board]::<host>[::<device>][::INSTR] VXI-11, device name defaults to inst0
+//     SIM[board]::INSTR                         in-process simulator
+//     REPLAY::<path>                            transcript recorded by `Recorder`
+//
+// IPv6 addresses are written in brackets, e.g. `TCPIP0::[fe80::1]::5555::SOCKET`.
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Resource {
+    Socket { board: u16, host: String, port: u16 },
+    Vxi11 { board: u16, host: String, device: String },
+    Simulator { board: u16 },
+    Replay(PathBuf),
+}
+
+fn invalid(resource: &str, reason: &str) -> Error {
+    Error::InvalidArgument(format!("invalid VISA resource {:?}: {}", resource, reason))
+}
+
+// Splits `TCPIP0` into the interface type and the board number.
+fn interface(field: &str) -> Option<(String, u16)> {
+    let upper = field.to_ascii_uppercase();
+    let name = upper.trim_end_matches(|c: char| c.is_ascii_digit());
+    let board = match &upper[name.len()..] {
+        "" => 0,
+        digits => digits.parse().ok()?,
+    };
+    Some((name.to_string(), board))
+}
+
+// Splits on `::` outside of the brackets around IPv6 addresses.
+fn fields(resource: &str) -> Vec<&str> {
+    let mut fields = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    let bytes = resource.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'[' => depth += 1,
+            b']' => depth -= 1,
+            b':' if depth == 0 && bytes.get(i + 1) == Some(&b':') => {
+                fields.push(&resource[start..i]);
+                i += 1;
+                start = i + 1;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    fields.push(&resource[start..]);
+    fields
+}
+
+fn host(resource: &str, field: &str) -> Result<String> {
+    let host = match field.strip_prefix('[') {
+        Some(rest) => rest.strip_suffix(']').ok_or_else(|| invalid(resource, "unterminated IPv6 address"))?,
+        None => field,
+    };
+    if host.is_empty() || host.contains(char::is_whitespace) {
+        return Err(invalid(resource, "missing host address"));
+    }
+    Ok(host.to_string())
+}
+
+impl Resource {
+    pub fn parse(resource: &str) -> Result<Resource> {
+        let trimmed = resource.trim();
+        if let Some(path) = trimmed.get(..8).filter(|p| p.eq_ignore_ascii_case("REPLAY::")).map(|_| &trimmed[8..]) {
+            if path.is_empty() {
+                return Err(invalid(resource, "missing transcript path"));
+            }
+            return Ok(Resource::Replay(PathBuf::from(path)));
+        }
+        let fields = fields(trimmed);
+        let (kind, board) = interface(fields[0]).ok_or_else(|| invalid(resource, "invalid board number"))?;
+        let suffix = fields.last().map(|f| f.to_ascii_uppercase()).unwrap_or_default();
+        match (kind.as_str(), fields.len()) {
+            ("SIM", 1) => Ok(Resource::Simulator { board }),
+            ("SIM", 2) if suffix == "INSTR" => Ok(Resource::Simulator { board }),
+            ("TCPIP", 4) if suffix == "SOCKET" => {
+                let port = fields[2].parse().map_err(|_| invalid(resource, "invalid port"))?;
+                Ok(Resource::Socket { board, host: host(resource, fields[1])?, port })
+            }
+            ("TCPIP", 2) => Ok(Resource::Vxi11 { board, host: host(resource, fields[1])?, device: "inst0".to_string() }),
+            ("TCPIP", 3) if suffix == "INSTR" => Ok(Resource::Vxi11 { board, host: host(resource, fields[1])?, device: "inst0".to_string() }),
+            ("TCPIP", 3) => Ok(Resource::Vxi11 { board, host: host(resource, fields[1])?, device: fields[2].to_string() }),
+            ("TCPIP", 4) if suffix == "INSTR" => Ok(Resource::Vxi11 { board, host: host(resource, fields[1])?, device: fields[2].to_string() }),
+            ("TCPIP" | "SIM", _) => Err(invalid(resource, "unexpected number of fields")),
+            _ => Err(invalid(resource, "unsupported interface type")),
+        }
+    }
+}
+
+impl FromStr for Resource {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Resource::parse(s)
+    }
+}
+
+fn fmt_host(host: &str) -> String {
+    if host.contains(':') { format!("[{}]", host) } else { host.to_string() }
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Resource::Socket { board, host, port } => write!(f, "TCPIP{}::{}::{}::SOCKET", board, fmt_host(host), port),
+            Resource::Vxi11 { board, host, device } => write!(f, "TCPIP{}::{}::{}::INSTR", board, fmt_host(host), device),
+            Resource::Simulator { board } => write!(f, "SIM{}::INSTR", board),
+            Resource::Replay(path) => write!(f, "REPLAY::{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Timeouts {
+    pub connect: Duration,
+    pub read: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts { connect: Duration::from_secs(5), read: Duration::from_secs(10) }
+    }
+}
+
+// The transport behind an opened resource.
+#[derive(Debug)]
+pub enum Transport {
+    // the stream and its peer, kept for reconnecting once the stream is dead
+    Socket(BufStream<TcpStream>, SocketAddr),
+    Vxi11(Vxi11),
+    Simulator(Box<Simulator>),
+    Replay(Replay),
+}
+
+macro_rules! delegate {
+    ($self:ident, $device:ident => $call:expr) => {
+        match $self {
+            Transport::Socket($device, _) => $call,
+            Transport::Vxi11($device) => $call,
+            Transport::Simulator($device) => $call,
+            Transport::Replay($device) => $call,
+        }
+    };
+}
+
+impl Visa for Transport {
+    fn write_scip_cmd(&mut self, buf: &[u8]) -> Result<()> {
+        delegate!(self, device => device.write_scip_cmd(buf))
+    }
+
+    fn read_result(&mut self) -> Result<String> {
+        delegate!(self, device => device.read_result())
+    }
+
+    fn read_result2(&mut self) -> Result<String> {
+        delegate!(self, device => device.read_result2())
+    }
+
+    fn read_block(&mut self) -> Result<Vec<u8>> {
+        delegate!(self, device => device.read_block())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        delegate!(self, device => device.clear())
+    }
+
+    fn discard(&mut self, len: usize) -> Result<()> {
+        delegate!(self, device => device.discard(len))
+    }
+
+    // Sockets reconnect to the same peer with the same read timeout.
+    fn reconnect(&mut self) -> Result<()> {
+        match self {
+            Transport::Socket(stream, address) => {
+                let timeouts = Timeouts { read: stream.get_ref().read_timeout()?.unwrap_or(Timeouts::default().read), ..Timeouts::default() };
+                *stream = BufStream::new(connect(&address.ip().to_string(), address.port(), timeouts)?);
+                Ok(())
+            }
+            Transport::Vxi11(device) => device.reconnect(),
+            Transport::Simulator(device) => device.reconnect(),
+            Transport::Replay(device) => device.reconnect(),
+        }
+    }
+}
+
+pub(crate) fn connect(host: &str, port: u16, timeouts: Timeouts) -> Result<TcpStream> {
+    let mut last_error = None;
+    for address in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, timeouts.connect) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(timeouts.read))?;
+                stream.set_nodelay(true)?;
+                return Ok(stream);
+            }
+            Err(err) => last_error = Some(err),
+        }
+    }
+    Err(match last_error {
+        Some(err) => err.into(),
+        None => Error::InvalidArgument(format!("{} does not resolve to any address", host)),
+    })
+}
+
+pub fn open(resource: &Resource) -> Result<Transport> {
+    open_with(resource, Timeouts::default())
+}
+
+pub fn open_with(resource: &Resource, timeouts: Timeouts) -> Result<Transport> {
+    match resource {
+        Resource::Socket { host, port, .. } => {
+            let stream = connect(host, *port, timeouts)?;
+            let address = stream.peer_addr()?;
+            Ok(Transport::Socket(BufStream::new(stream), address))
+        }
+        Resource::Vxi11 { host, device, .. } => Ok(Transport::Vxi11(Vxi11::connect(host, device, timeouts)?)),
+        Resource::Simulator { .. } => Ok(Transport::Simulator(Box::default())),
+        Resource::Replay(path) => Ok(Transport::Replay(Replay::open(path)?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use crate::simulator::{self, Instrument};
+
+    fn socket(host: &str, port: u16) -> Resource {
+        Resource::Socket { board: 0, host: host.to_string(), port }
+    }
+
+    fn vxi11(host: &str, device: &str) -> Resource {
+        Resource::Vxi11 { board: 0, host: host.to_string(), device: device.to_string() }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Resource::parse("TCPIP0::192.168.1.20::5555::SOCKET").unwrap(), socket("192.168.1.20", 5555));
+        assert_eq!(Resource::parse("tcpip::scope-3.lab::5555::socket").unwrap(), socket("scope-3.lab", 5555));
+        assert_eq!(Resource::parse("TCPIP0::[fe80::1%eth0]::5555::SOCKET").unwrap(), socket("fe80::1%eth0", 5555));
+        assert_eq!(Resource::parse("TCPIP0::192.168.1.20::INSTR").unwrap(), vxi11("192.168.1.20", "inst0"));
+        assert_eq!(Resource::parse("TCPIP::scope").unwrap(), vxi11("scope", "inst0"));
+        assert_eq!(Resource::parse("TCPIP1::[::1]::inst1::INSTR").unwrap(), Resource::Vxi11 { board: 1, host: "::1".to_string(), device: "inst1".to_string() });
+        assert_eq!(Resource::parse("SIM::INSTR").unwrap(), Resource::Simulator { board: 0 });
+        assert_eq!(Resource::parse("REPLAY::captures/ds1102z.log").unwrap(), Resource::Replay(PathBuf::from("captures/ds1102z.log")));
+        for bad in ["", "GPIB0::7::INSTR", "TCPIP0::host::port::SOCKET", "TCPIP0::[fe80::1::5555::SOCKET", "TCPIP0::::5555::SOCKET", "REPLAY::"] {
+            assert!(matches!(Resource::parse(bad), Err(Error::InvalidArgument(_))), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_display_roundtrip() {
+        for text in ["TCPIP0::192.168.1.20::5555::SOCKET", "TCPIP0::[fe80::1]::5555::SOCKET", "TCPIP2::scope::inst0::INSTR", "SIM0::INSTR"] {
+            assert_eq!(Resource::parse(text).unwrap().to_string(), text);
+        }
+    }
+
+    #[test]
+    fn test_open_socket_and_simulator() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || simulator::serve(listener, Arc::new(Mutex::new(Instrument::default()))));
+
+        let resource: Resource = format!("TCPIP0::localhost::{}::SOCKET", port).parse().unwrap();
+        for resource in [resource, "SIM::INSTR".parse().unwrap()] {
+            let mut device = open(&resource).unwrap();
+            device.write_scip_cmd(b"*IDN?\n").unwrap();
+            assert!(device.read_result().unwrap().starts_with("RIGOL TECHNOLOGIES,DS1104Z"));
+        }
+    }
+
+    #[test]
+    fn test_reconnect_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || simulator::serve(listener, Arc::new(Mutex::new(Instrument::default()))));
+
+        let device = open(&socket("127.0.0.1", port)).unwrap();
+        let mut scope = crate::Ds1000z::new(device).unwrap();
+        scope.set_recovery(crate::Recovery::Reconnect);
+        if let Transport::Socket(stream, _) = scope.device() {
+            stream.get_ref().shutdown(std::net::Shutdown::Both).unwrap();
+        }
+        assert_eq!(scope.query(":WAV:FORM?").unwrap(), "BYTE");
+    }
+}
@@ -0,0 +1,93 @@
+// SCPI mnemonics are written with the short form in upper case and the rest of the
+// long form in lower case, e.g. `CHANnel1` accepts `CHAN1` and `CHANNEL1` in any case.
+
+// The short form of `mnemonic`: its upper case letters followed by any numeric suffix.
+pub fn short_form(mnemonic: &str) -> String {
+    let suffix = mnemonic.trim_start_matches(|c: char| !c.is_ascii_digit());
+    let stem = &mnemonic[..mnemonic.len() - suffix.len()];
+    stem.chars().filter(|c| !c.is_ascii_lowercase()).chain(suffix.chars()).collect()
+}
+
+// Whether `s` is the short or long form of `mnemonic`, ignoring case.
+pub fn matches(mnemonic: &str, s: &str) -> bool {
+    s.eq_ignore_ascii_case(mnemonic) || s.eq_ignore_ascii_case(&short_form(mnemonic))
+}
+
+// Declares an enum of SCPI mnemonics with case insensitive short/long form `FromStr`,
+// a `Display` that writes the short form, and an `ALL` list of the variants:
+//
+//     scpi_enum! {
+//         pub enum Mode {
+//             NORM => "NORMal",
+//             MAX => "MAXimum",
+//             RAW => "RAW",
+//         }
+//     }
+macro_rules! scpi_enum {
+    ($(#[$meta:meta])* $vis:vis enum $name:ident { $($variant:ident => $mnemonic:literal),+ $(,)? }) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        $vis enum $name {
+            $($variant),+
+        }
+
+        impl $name {
+            pub const ALL: &[$name] = &[$($name::$variant),+];
+
+            // The mnemonic in SCPI case, e.g. `CHANnel1`.
+            pub fn mnemonic(&self) -> &'static str {
+                match self {
+                    $($name::$variant => $mnemonic),+
+                }
+            }
+        }
+
+        impl std::str::FromStr for $name {
+            type Err = $crate::error::Error;
+
+            fn from_str(s: &str) -> $crate::error::Result<Self> {
+                let trimmed = s.trim();
+                $name::ALL.iter()
+                    .copied()
+                    .find(|value| $crate::scpi::matches(value.mnemonic(), trimmed))
+                    .ok_or_else(|| $crate::error::Error::parse_error(s, stringify!($name)))
+            }
+        }
+
+        impl $crate::property::PropertyValue for $name {
+            fn parse(response: &str) -> $crate::error::Result<Self> {
+                response.parse()
+            }
+
+            fn argument(&self) -> String {
+                self.to_string()
+            }
+        }
+
+        impl std::fmt::Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "{}", $crate::scpi::short_form(self.mnemonic()))
+            }
+        }
+    };
+}
+
+pub(crate) use scpi_enum;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mnemonic_forms() {
+        assert_eq!(short_form("CHANnel1"), "CHAN1");
+        assert_eq!(short_form("NORMal"), "NORM");
+        assert_eq!(short_form("D15"), "D15");
+        assert!(matches("CHANnel1", "chan1"));
+        assert!(matches("CHANnel1", "CHANnel1"));
+        assert!(matches("CHANnel1", "channel1"));
+        assert!(!matches("CHANnel1", "CHANN1"));
+        assert!(!matches("CHANnel1", "CHAN2"));
+        assert!(!matches("D1", "D15"));
+    }
+}
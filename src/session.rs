@@ -0,0 +1,455 @@
+use crate::batch::{Batch, Reply};
+use crate::cache::SettingsCache;
+use crate::command::CHANnelCommand::CHANnelCommand;
+use crate::command::TRIGgerCommand::{TRIGgerCommand, TRIGgerState};
+use crate::command::WAVeformCommand::{MemoryDepth, WAVeformCommands, WAVeformState};
+use crate::block::BlockError;
+use crate::device::Visa;
+use crate::error::{Error, InstrumentError, Result};
+use crate::firmware::{Quirk, QuirkRegistry};
+use crate::identity::Identity;
+use crate::model::Capabilities;
+
+// Enough for the lines of a 24 Mpts block cut short.
+pub(crate) const MAX_STALE_LINES: usize = 1_000_000;
+
+// What a query does when its reply does not arrive or is not what was asked for, which
+// usually means a late reply to an earlier query is still in the pipe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Recovery {
+    // return the error
+    Off,
+    // resynchronize (see `Ds1000z::resync`) and retry the query once
+    Resync,
+    // reconnect the transport, resynchronize and retry the query once
+    Reconnect,
+}
+
+impl Recovery {
+    fn applies_to(self, err: &Error) -> bool {
+        match err {
+            Error::Timeout(_)
+            | Error::BlockError(BlockError::InvalidHeader(_))
+            | Error::BlockError(BlockError::MissingTerminator(Some(_))) => self != Recovery::Off,
+            Error::IoError(_) | Error::BlockError(_) => self == Recovery::Reconnect,
+            _ => false,
+        }
+    }
+}
+
+// The replies a resync waits for: `*ESE?` with the event status enable mask as it was,
+// `*ESE?` after writing the marker `<n>` in its place, and `*OPC?`. A lone `*OPC?`
+// could not tell its own "1" from a late reply to an earlier one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Sentinel {
+    mark: String,
+    // the last two replies
+    before: Option<String>,
+    previous: Option<String>,
+}
+
+impl Sentinel {
+    pub(crate) fn new(mark: u8) -> Sentinel {
+        Sentinel { mark: mark.to_string(), before: None, previous: None }
+    }
+
+    pub(crate) fn commands(&self) -> [String; 4] {
+        ["*ESE?".to_string(), format!("*ESE {}", self.mark), "*ESE?".to_string(), "*OPC?".to_string()]
+    }
+
+    // Takes the next reply, `None` for one that is not text, and returns the original
+    // mask once the sentinel's replies have all arrived.
+    pub(crate) fn scan(&mut self, line: Option<&str>) -> Option<u8> {
+        let line = line.map(|line| line.trim().to_string());
+        let found = match (&self.before, &self.previous, line.as_deref()) {
+            (Some(original), Some(mark), Some("1")) if *mark == self.mark => original.parse().ok(),
+            _ => None,
+        };
+        self.before = self.previous.take();
+        self.previous = line;
+        found
+    }
+}
+
+// The error queue holds at most this many entries.
+const ERROR_QUEUE_SIZE: usize = 64;
+
+// `refresh_all` reads the cached settings in compound queries of at most this many.
+const REFRESH_BATCH_SIZE: usize = 16;
+
+// When commands written by `Ds1000z::write` are checked against the instrument's error
+// queue. Queries are not checked; a bad query shows up as a timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorChecking {
+    Off,
+    // `:SYSTem:ERRor?` after every command, so the error comes back from the call that
+    // caused it
+    AfterEachWrite,
+    // drain the queue after every `n` commands and on `check_errors`
+    Batched(usize),
+}
+
+// A connection to one oscilloscope. The session owns the transport, so every SCPI
+// exchange goes through it and the subsystem handles it lends out (`trigger()`,
+// `waveform()`, `channel(n)`) can never interleave their commands and replies.
+#[derive(Debug)]
+pub struct Ds1000z<V: Visa> {
+    pub(crate) device: V,
+    pub(crate) trigger_state: TRIGgerState,
+    pub(crate) waveform_state: WAVeformState,
+    recovery: Recovery,
+    sentinel: u8,
+    error_checking: ErrorChecking,
+    unchecked_writes: usize,
+    pub(crate) cache: SettingsCache,
+    identity: Option<Identity>,
+    quirk_registry: QuirkRegistry,
+    quirks: Vec<Quirk>,
+}
+
+impl<V: Visa> Ds1000z<V> {
+    // Recovery starts as `Recovery::Resync`: a query that times out or gets a reply that
+    // is out of step is sent again after `resync`, which keeps the status registers.
+    pub fn new(device: V) -> Result<Ds1000z<V>> {
+        Ds1000z::with_quirks(device, QuirkRegistry::new())
+    }
+
+    // Identifies the scope first, so the quirks `registry` lists for its model and
+    // firmware apply from the first setting read.
+    pub fn with_quirks(device: V, registry: QuirkRegistry) -> Result<Ds1000z<V>> {
+        let mut scope = Ds1000z {
+            device,
+            trigger_state: TRIGgerState::default(),
+            waveform_state: WAVeformState::new(),
+            recovery: Recovery::Resync,
+            sentinel: 0,
+            error_checking: ErrorChecking::Off,
+            unchecked_writes: 0,
+            cache: SettingsCache::new(),
+            identity: None,
+            quirk_registry: registry,
+            quirks: Vec::new(),
+        };
+        scope.identity()?;
+        scope.trigger().get_sweep()?;
+        scope.waveform().init()?;
+        Ok(scope)
+    }
+
+    pub fn trigger(&mut self) -> TRIGgerCommand<'_, V> {
+        TRIGgerCommand::new(self)
+    }
+
+    pub fn waveform(&mut self) -> WAVeformCommands<'_, V> {
+        WAVeformCommands::new(self)
+    }
+
+    // Channels beyond 4 are refused, and beyond the model's analog channels once the
+    // scope has been identified.
+    pub fn channel(&mut self, channel: u8) -> Result<CHANnelCommand<'_, V>> {
+        let channels = self.identity.as_ref().and_then(Identity::capabilities).map_or(4, |capabilities| capabilities.analog_channels);
+        if !(1..=channels).contains(&channel) {
+            return Err(Error::InvalidArgument(format!("channel {} does not exist", channel)));
+        }
+        Ok(CHANnelCommand::new(self, channel))
+    }
+
+    pub fn batch(&mut self) -> Batch<'_, V> {
+        Batch::new(self)
+    }
+
+    // Asks `*IDN?` on first use.
+    pub fn identity(&mut self) -> Result<&Identity> {
+        if self.identity.is_none() {
+            let identity = Identity::parse(&self.query("*IDN?")?)?;
+            self.quirks = self.quirk_registry.quirks_for(&identity);
+            self.identity = Some(identity);
+        }
+        Ok(self.identity.as_ref().unwrap())
+    }
+
+    // The workarounds the transfer and parsing code applies for this scope.
+    pub fn quirks(&self) -> &[Quirk] {
+        &self.quirks
+    }
+
+    // The model's analog channels, or 4 when it is not in the capability table.
+    pub(crate) fn analog_channels(&mut self) -> Result<u8> {
+        Ok(self.identity()?.capabilities().map_or(4, |capabilities| capabilities.analog_channels))
+    }
+
+    // The model's deepest memory, or 24 Mpts when it is not in the capability table.
+    pub(crate) fn max_memory_depth(&mut self) -> Result<u32> {
+        Ok(self.identity()?.capabilities().map_or(MemoryDepth::DS1102Z_E as u32, Capabilities::max_memory_depth))
+    }
+
+    pub fn capabilities(&mut self) -> Result<&'static Capabilities> {
+        let identity = self.identity()?;
+        identity.capabilities().ok_or_else(|| Error::Unsupported { feature: "The DS1000Z command set".to_string(), model: identity.model.clone() })
+    }
+
+    pub fn cache(&self) -> &SettingsCache {
+        &self.cache
+    }
+
+    // With caching off nothing is remembered between calls and the waveform commands
+    // read the settings they depend on before every use.
+    pub fn set_caching(&mut self, enabled: bool) {
+        self.cache.set_enabled(enabled);
+    }
+
+    // Reads every cached setting and the trigger and waveform state again, e.g. after
+    // the front panel was used.
+    pub fn refresh_all(&mut self) -> Result<()> {
+        let headers: Vec<String> = self.cache.headers().map(str::to_string).collect();
+        self.cache.clear();
+        for headers in headers.chunks(REFRESH_BATCH_SIZE) {
+            let mut batch = self.batch();
+            let queries: Vec<Reply<String>> = headers.iter().map(|header| batch.query(&format!("{}?", header))).collect();
+            let replies = batch.send()?;
+            for (header, query) in headers.iter().zip(queries) {
+                self.cache.store(header, replies.text(query)?);
+            }
+        }
+        self.trigger().get_sweep()?;
+        self.waveform().refresh()
+    }
+
+    pub fn recovery(&self) -> Recovery {
+        self.recovery
+    }
+
+    pub fn set_recovery(&mut self, recovery: Recovery) {
+        self.recovery = recovery;
+    }
+
+    pub fn error_checking(&self) -> ErrorChecking {
+        self.error_checking
+    }
+
+    pub fn set_error_checking(&mut self, error_checking: ErrorChecking) {
+        self.error_checking = error_checking;
+        self.unchecked_writes = 0;
+    }
+
+    // Reads the error queue until it reports no error. Reading the queue takes entries
+    // off it, so a read is never retried: a timeout is returned as is.
+    pub fn drain_errors(&mut self) -> Result<Vec<InstrumentError>> {
+        self.unchecked_writes = 0;
+        let mut errors = Vec::new();
+        for _ in 0..ERROR_QUEUE_SIZE {
+            self.send(":SYSTem:ERRor?")?;
+            match InstrumentError::parse(&self.device.read_result()?, None)? {
+                Some(err) => errors.push(err),
+                None => break,
+            }
+        }
+        Ok(errors)
+    }
+
+    // Drains the error queue and returns its entries as an error, oldest first.
+    pub fn check_errors(&mut self) -> Result<()> {
+        let mut errors = self.drain_errors()?;
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(Error::InstrumentError(errors.remove(0))),
+            _ => Err(Error::InstrumentErrors(errors)),
+        }
+    }
+
+    pub fn write(&mut self, command: &str) -> Result<()> {
+        self.cache.invalidate(command);
+        self.send(command)?;
+        self.written(command, 1)
+    }
+
+    // Counts `commands` sent in `message` and checks the error queue when the error
+    // checking policy says so.
+    pub(crate) fn written(&mut self, message: &str, commands: usize) -> Result<()> {
+        self.unchecked_writes += commands;
+        match self.error_checking {
+            // the newest entry is the one this command caused
+            ErrorChecking::AfterEachWrite => self.check_errors().map_err(|err| match err {
+                Error::InstrumentError(err) => Error::InstrumentError(InstrumentError { command: Some(message.to_string()), ..err }),
+                Error::InstrumentErrors(mut errors) => {
+                    if let Some(last) = errors.last_mut() {
+                        last.command = Some(message.to_string());
+                    }
+                    Error::InstrumentErrors(errors)
+                }
+                err => err,
+            }),
+            ErrorChecking::Batched(n) if self.unchecked_writes >= n => self.check_errors(),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn send(&mut self, command: &str) -> Result<()> {
+        let mut buf = Vec::with_capacity(command.len() + 1);
+        buf.extend_from_slice(command.as_bytes());
+        buf.push(b'\n');
+        self.device.write_scip_cmd(&buf)
+    }
+
+    pub fn query(&mut self, command: &str) -> Result<String> {
+        self.exchange(command, V::read_result)
+    }
+
+    pub fn query_block(&mut self, command: &str) -> Result<Vec<u8>> {
+        self.exchange(command, V::read_block)
+    }
+
+    // Queries only read settings, so they are safe to send again after a resync.
+    fn exchange<T>(&mut self, command: &str, read: fn(&mut V) -> Result<T>) -> Result<T> {
+        let result = self.send(command).and_then(|_| read(&mut self.device));
+        match result {
+            Err(err) if self.recovery.applies_to(&err) => {
+                self.resync()?;
+                self.send(command)?;
+                read(&mut self.device)
+            }
+            result => result,
+        }
+    }
+
+    // Brings commands and replies back in step: reconnects if the recovery policy says
+    // so, clears the device, then sends the `Sentinel` queries and discards every reply
+    // up to their answers. The event status enable mask is put back as it was and the
+    // error queue is left alone.
+    pub fn resync(&mut self) -> Result<()> {
+        if self.recovery == Recovery::Reconnect {
+            self.device.reconnect()?;
+        }
+        self.device.clear()?;
+        self.sentinel = self.sentinel % 254 + 2;
+        let mut sentinel = Sentinel::new(self.sentinel);
+        for command in sentinel.commands() {
+            self.send(&command)?;
+        }
+        for _ in 0..MAX_STALE_LINES {
+            let line = match self.device.read_result() {
+                Ok(line) => Some(line),
+                // the binary tail of an abandoned block
+                Err(Error::ProtocolError(_)) => None,
+                Err(err) => return Err(err),
+            };
+            if let Some(mask) = sentinel.scan(line.as_deref()) {
+                return self.send(&format!("*ESE {}", mask));
+            }
+        }
+        Err(Error::ProtocolError(format!("no sentinel after discarding {} replies", MAX_STALE_LINES)))
+    }
+
+    pub fn device(&mut self) -> &mut V {
+        &mut self.device
+    }
+
+    pub fn into_inner(self) -> V {
+        self.device
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::WAVeformCommand::{MaxTransferSize, Source};
+    use crate::simulator::Simulator;
+
+    fn scope(error_checking: ErrorChecking) -> Ds1000z<Simulator> {
+        let mut scope = Ds1000z::new(Simulator::new()).unwrap();
+        scope.set_error_checking(error_checking);
+        scope
+    }
+
+    #[test]
+    fn test_error_after_each_write() {
+        let mut scope = scope(ErrorChecking::AfterEachWrite);
+        scope.waveform().set_source(Source::CHAN2).unwrap();
+        match scope.write(":WAVeform:STARt -5") {
+            Err(Error::InstrumentError(err)) => {
+                assert_eq!((err.code, err.message.as_str()), (-222, "Data out of range"));
+                assert_eq!(err.command.as_deref(), Some(":WAVeform:STARt -5"));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        scope.write(":WAVeform:BOGus 1").unwrap_err();
+        assert!(scope.drain_errors().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_batched_errors() {
+        let mut scope = scope(ErrorChecking::Batched(3));
+        scope.write(":WAVeform:FORMat DOUBLE").unwrap();
+        scope.write(":WAVeform:BOGus 1").unwrap();
+        match scope.write(":WAVeform:SOURce CHAN2") {
+            Err(Error::InstrumentErrors(errors)) => {
+                let entries: Vec<(i32, Option<String>)> = errors.into_iter().map(|err| (err.code, err.command)).collect();
+                assert_eq!(entries, [(-224, None), (-113, None)]);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        scope.set_error_checking(ErrorChecking::Off);
+        scope.write(":WAVeform:BOGus 1").unwrap();
+        scope.write(":WAVeform:BOGus 2").unwrap();
+        let codes: Vec<i32> = scope.drain_errors().unwrap().iter().map(|err| err.code).collect();
+        assert_eq!(codes, [-113, -113]);
+    }
+
+    #[test]
+    fn test_settings_cache() {
+        use crate::command::{CHANnelCommand, WAVeformCommand};
+        let mut scope = scope(ErrorChecking::Off);
+        assert_eq!(WAVeformCommand::SOURCE.cached(&scope), Some(Source::CHAN1));
+        CHANnelCommand::SCALE[0].get(&mut scope).unwrap();
+        CHANnelCommand::SCALE[1].get(&mut scope).unwrap();
+        scope.write(":CHANnel2:SCALe 2").unwrap();
+        assert_eq!(CHANnelCommand::SCALE[0].cached(&scope), Some(1.0));
+        assert_eq!(CHANnelCommand::SCALE[1].cached(&scope), None);
+        assert_eq!(CHANnelCommand::SCALE[1].current(&mut scope).unwrap(), 2.0);
+
+        // changed behind the session's back, e.g. on the front panel
+        scope.device().instrument_mut().source = Source::CHAN3;
+        scope.device().instrument_mut().channels[0].scale = 0.5;
+        assert_eq!(WAVeformCommand::SOURCE.cached(&scope), Some(Source::CHAN1));
+        for channel in 1..=4 {
+            let mut channel = scope.channel(channel).unwrap();
+            channel.get_display().unwrap();
+            channel.get_offset().unwrap();
+            channel.get_scale().unwrap();
+        }
+        assert!(scope.cache().len() > REFRESH_BATCH_SIZE);
+        scope.refresh_all().unwrap();
+        assert_eq!(scope.waveform().current_source().unwrap(), Source::CHAN3);
+        assert_eq!(WAVeformCommand::SOURCE.cached(&scope), Some(Source::CHAN3));
+        assert_eq!(CHANnelCommand::SCALE[0].cached(&scope), Some(0.5));
+
+        // the waveform settings follow commands written around `waveform()`
+        scope.write(":WAVeform:FORMat WORD").unwrap();
+        assert_eq!(scope.waveform().max_transfer_size().unwrap(), MaxTransferSize::WORD);
+
+        scope.set_caching(false);
+        assert!(scope.cache().is_empty());
+        assert_eq!(CHANnelCommand::SCALE[0].current(&mut scope).unwrap(), 0.5);
+        assert!(scope.cache().is_empty());
+    }
+
+    #[test]
+    fn test_identify_model() {
+        let mut scope = scope(ErrorChecking::Off);
+        assert_eq!(scope.identity().unwrap().serial, "DS1ZA000000001");
+        assert_eq!(scope.capabilities().unwrap().model, "DS1104Z");
+        assert!(scope.channel(4).is_ok());
+
+        let mut device = Simulator::new();
+        device.instrument_mut().idn = "RIGOL TECHNOLOGIES,DS1202Z-E,DS1ZE000000001,00.06.02".to_string();
+        let mut scope = Ds1000z::new(device).unwrap();
+        assert_eq!(scope.capabilities().unwrap().bandwidth, 200e6);
+        assert!(matches!(scope.channel(3), Err(Error::InvalidArgument(_))));
+        assert!(scope.channel(2).is_ok());
+
+        let mut device = Simulator::new();
+        device.instrument_mut().idn = "RIGOL TECHNOLOGIES,DS2202A,DS2A000000001,00.03.05".to_string();
+        let mut scope = Ds1000z::new(device).unwrap();
+        assert!(matches!(scope.capabilities(), Err(Error::Unsupported { model, .. }) if model == "DS2202A"));
+    }
+}
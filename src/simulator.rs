@@ -0,0 +1,679 @@
+use std::f64::consts::PI;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use crate::command::TRIGgerCommand::SWEep;
+use crate::command::WAVeformCommand::{Format, MaxTransferSize, Mode, Source};
+use crate::firmware::{self, Quirk};
+use crate::model;
+use crate::number::parse_argument;
+
+// An in-process DS1000Z. `Instrument` is the command model: it parses the SCPI program
+// messages this crate emits, keeps the settings and renders synthetic acquisitions.
+// `Simulator` wraps it in a byte stream (write commands, read responses), so it is a
+// `Visa` like any other transport.
+
+const SCREEN_POINTS: u32 = 1200;
+const MEMORY_POINTS: u32 = 24_000_000;
+const Y_REFERENCE: i32 = 127;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Signal {
+    Sine { frequency: f64, amplitude: f64 },
+    Square { frequency: f64, amplitude: f64 },
+    Noise { amplitude: f64, seed: u64 },
+    Dc(f64),
+}
+
+impl Signal {
+    pub fn voltage(&self, time: f64, index: u64) -> f64 {
+        match *self {
+            Signal::Sine { frequency, amplitude } => amplitude * (2.0 * PI * frequency * time).sin(),
+            Signal::Square { frequency, amplitude } => {
+                if (frequency * time).rem_euclid(1.0) < 0.5 { amplitude } else { -amplitude }
+            }
+            Signal::Noise { amplitude, seed } => {
+                // splitmix64, so any sample can be rendered without generating the ones before it
+                let mut z = seed ^ index.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+                z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+                z ^= z >> 31;
+                amplitude * ((z >> 11) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0)
+            }
+            Signal::Dc(level) => level,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Channel {
+    pub display: bool,
+    pub scale: f64,
+    pub offset: f64,
+    pub signal: Signal,
+}
+
+impl Channel {
+    fn y_increment(&self) -> f64 {
+        self.scale / 25.0
+    }
+
+    fn y_origin(&self) -> i32 {
+        (self.offset / self.y_increment()).round() as i32
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Instrument {
+    pub idn: String,
+    pub channels: [Channel; 4],
+    pub timebase_scale: f64,
+    pub memory_depth: Option<u32>,
+    pub running: bool,
+    pub sweep: SWEep,
+    pub source: Source,
+    pub mode: Mode,
+    pub format: Format,
+    pub start: u32,
+    pub stop: u32,
+    pub errors: Vec<(i32, String)>,
+    pub event_status_enable: u8,
+    // firmware misbehavior to reproduce
+    pub quirks: Vec<Quirk>,
+}
+
+impl Default for Instrument {
+    fn default() -> Self {
+        let channel = |display, signal| Channel { display, scale: 1.0, offset: 0.0, signal };
+        Instrument {
+            idn: "RIGOL TECHNOLOGIES,DS1104Z,DS1ZA000000001,00.04.04.SP3".to_string(),
+            channels: [
+                channel(true, Signal::Sine { frequency: 1e3, amplitude: 1.0 }),
+                channel(false, Signal::Square { frequency: 500.0, amplitude: 2.0 }),
+                channel(false, Signal::Noise { amplitude: 0.2, seed: 3 }),
+                channel(false, Signal::Dc(0.5)),
+            ],
+            timebase_scale: 1e-3,
+            memory_depth: None,
+            running: true,
+            sweep: SWEep::AUTO,
+            source: Source::CHAN1,
+            mode: Mode::NORM,
+            format: Format::BYTE,
+            start: 1,
+            stop: SCREEN_POINTS,
+            errors: Vec::new(),
+            event_status_enable: 0,
+            quirks: Vec::new(),
+        }
+    }
+}
+
+const MNEMONICS: &[&str] = &[
+    "ACQuire", "CHANnel", "CLEar", "DATA", "DISPlay", "ERRor", "FORMat", "IDN", "MAIN",
+    "MDEPth", "MODE", "NEXT", "OFFSet", "PREamble", "RUN", "SCALe", "SINGle", "SOURce",
+    "SRATe", "STARt", "STOP", "SWEep", "SYSTem", "TIMebase", "TRIGger", "WAVeform",
+    "XINCrement", "XORigin", "XREFerence", "YINCrement", "YORigin", "YREFerence",
+];
+
+// Reduces one header node to its short form, e.g. `waveform` and `WAV` both become `WAV`
+// and `CHANnel2` becomes `CHAN2`.
+fn short_node(node: &str) -> String {
+    let upper = node.to_ascii_uppercase();
+    let name = upper.trim_end_matches(|c: char| c.is_ascii_digit());
+    let suffix = &upper[name.len()..];
+    for mnemonic in MNEMONICS {
+        let short: String = mnemonic.chars().filter(|c| !c.is_ascii_lowercase()).collect();
+        if name == short || name == mnemonic.to_ascii_uppercase() {
+            return short + suffix;
+        }
+    }
+    upper
+}
+
+fn nr3(value: f64) -> String {
+    let text = format!("{:.6e}", value);
+    let (mantissa, exponent) = text.split_once('e').unwrap_or((&text, "0"));
+    let exponent: i32 = exponent.parse().unwrap_or(0);
+    format!("{}e{}{:02}", mantissa, if exponent < 0 { '-' } else { '+' }, exponent.abs())
+}
+
+fn block(payload: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(format!("#9{:09}", payload.len()).as_bytes());
+    out.extend_from_slice(payload);
+}
+
+impl Instrument {
+    // The channels of the model in `idn`, all four for a model not in the table.
+    pub fn analog_channels(&self) -> usize {
+        let model = self.idn.split(',').nth(1).unwrap_or("");
+        model::lookup(model).map_or(4, |capabilities| capabilities.analog_channels as usize)
+    }
+
+    pub fn enabled_channels(&self) -> usize {
+        self.channels.iter().filter(|ch| ch.display).count().max(1)
+    }
+
+    pub fn max_memory_depth(&self) -> u32 {
+        match self.enabled_channels() {
+            1 => MEMORY_POINTS,
+            2 => MEMORY_POINTS / 2,
+            _ => MEMORY_POINTS / 4,
+        }
+    }
+
+    pub fn acquired_depth(&self) -> u32 {
+        self.memory_depth.unwrap_or_else(|| self.max_memory_depth())
+    }
+
+    pub fn points(&self) -> u32 {
+        match self.mode {
+            Mode::NORM => SCREEN_POINTS,
+            Mode::MAX if self.running => SCREEN_POINTS,
+            Mode::MAX | Mode::RAW => self.acquired_depth(),
+        }
+    }
+
+    pub fn x_increment(&self) -> f64 {
+        self.timebase_scale * 12.0 / self.points() as f64
+    }
+
+    pub fn x_origin(&self) -> f64 {
+        -self.timebase_scale * 6.0
+    }
+
+    fn channel_of(&self, source: Source) -> Channel {
+        match source {
+            Source::CHAN2 => self.channels[1],
+            Source::CHAN3 => self.channels[2],
+            Source::CHAN4 => self.channels[3],
+            _ => self.channels[0],
+        }
+    }
+
+    pub fn voltage(&self, source: Source, index: u32) -> f64 {
+        let time = self.x_origin() + index as f64 * self.x_increment();
+        let at = |ch: usize| self.channels[ch].signal.voltage(time, index as u64);
+        match source {
+            Source::CHAN1 => at(0),
+            Source::CHAN2 => at(1),
+            Source::CHAN3 => at(2),
+            Source::CHAN4 => at(3),
+            Source::MATH => at(0) + at(1),
+            _ => 0.0,
+        }
+    }
+
+    pub fn sample(&self, source: Source, index: u32) -> u8 {
+        let ch = self.channel_of(source);
+        let code = self.voltage(source, index) / ch.y_increment() + (ch.y_origin() + Y_REFERENCE) as f64;
+        code.round().clamp(0.0, 255.0) as u8
+    }
+
+    fn preamble(&self) -> String {
+        let ch = self.channel_of(self.source);
+        let format = match self.format { Format::BYTE => 0, Format::WORD => 1, Format::ASC => 2 };
+        let mode = match self.mode { Mode::NORM => 0, Mode::MAX => 1, Mode::RAW => 2 };
+        format!("{},{},{},{},{},{},0,{},{},{}",
+            format, mode, self.integer(self.points()), self.integer(1), nr3(self.x_increment()), nr3(self.x_origin()),
+            nr3(ch.y_increment()), ch.y_origin(), Y_REFERENCE)
+    }
+
+    fn integer(&self, value: u32) -> String {
+        if self.quirks.contains(&Quirk::Nr3Integers) { nr3(value as f64) } else { value.to_string() }
+    }
+
+    fn data_range(&self) -> Range<u32> {
+        let stop = self.stop.min(self.points());
+        let max = MaxTransferSize::from(self.format) as u32;
+        if self.start > stop { 0..0 } else { self.start - 1..stop.min(self.start - 1 + max) }
+    }
+
+    // The bytes `Quirk::TrailingBlockBytes` sends after the terminator of a full BYTE block.
+    fn trailer(&self) -> usize {
+        let full_block = self.format == Format::BYTE && self.data_range().len() == MaxTransferSize::BYTE as usize;
+        firmware::trailing_block_bytes(&self.quirks, full_block)
+    }
+
+    fn data(&self, out: &mut Vec<u8>) {
+        let range = self.data_range();
+        let mut payload = Vec::new();
+        match self.format {
+            Format::BYTE => payload.extend(range.map(|i| self.sample(self.source, i))),
+            Format::WORD => {
+                for i in range {
+                    payload.extend_from_slice(&(self.sample(self.source, i) as u16).to_le_bytes());
+                }
+            }
+            Format::ASC => {
+                let ch = self.channel_of(self.source);
+                let values: Vec<String> = range
+                    .map(|i| nr3((self.sample(self.source, i) as i32 - ch.y_origin() - Y_REFERENCE) as f64 * ch.y_increment()))
+                    .collect();
+                payload.extend_from_slice(values.join(",").as_bytes());
+            }
+        }
+        block(&payload, out);
+    }
+
+    fn push_error(&mut self, code: i32, message: &str) {
+        self.errors.push((code, message.to_string()));
+    }
+
+    // Executes one program message (one line without its terminator) and appends the
+    // response, if any, to `out`.
+    pub fn execute(&mut self, message: &str, out: &mut Vec<u8>) {
+        let mut path: Vec<String> = Vec::new();
+        let mut responded = false;
+        let mut trailer = 0;
+        for unit in message.split(';') {
+            let unit = unit.trim();
+            if unit.is_empty() {
+                continue;
+            }
+            let (header, args) = match unit.find(char::is_whitespace) {
+                Some(i) => (&unit[..i], unit[i..].trim()),
+                None => (unit, ""),
+            };
+            let query = header.ends_with('?');
+            let header = header.trim_end_matches('?');
+            let mut nodes: Vec<String> = if header.starts_with('*') {
+                vec![header.to_ascii_uppercase()]
+            } else if let Some(absolute) = header.strip_prefix(':') {
+                absolute.split(':').map(short_node).collect()
+            } else {
+                path.iter().cloned().chain(header.split(':').map(short_node)).collect()
+            };
+            if nodes.first().map(String::as_str) == Some("TIM") && nodes.get(1).map(String::as_str) == Some("MAIN") {
+                nodes.remove(1);
+            }
+            if !header.starts_with('*') {
+                path = nodes[..nodes.len() - 1].to_vec();
+            }
+            let start = out.len();
+            if responded && query {
+                out.push(b';');
+            }
+            let before = out.len();
+            let nodes: Vec<&str> = nodes.iter().map(String::as_str).collect();
+            if query && nodes == ["WAV", "DATA"] {
+                trailer += self.trailer();
+            }
+            self.dispatch(&nodes, query, args, out);
+            if out.len() > before {
+                responded = true;
+            } else {
+                out.truncate(start);
+            }
+        }
+        if responded {
+            out.push(b'\n');
+            out.resize(out.len() + trailer, b'\n');
+        }
+    }
+
+    fn dispatch(&mut self, nodes: &[&str], query: bool, args: &str, out: &mut Vec<u8>) {
+        let arg = args.to_ascii_uppercase();
+        let number = parse_argument(args);
+        let mut text = None;
+        let mut reply = |response: String| text = Some(response);
+        match (nodes, query) {
+            (["*IDN"], true) => reply(self.idn.clone()),
+            (["*OPC"], true) => reply("1".to_string()),
+            (["*OPC"], false) => {}
+            (["*RST"], false) => {
+                *self = Instrument {
+                    idn: self.idn.clone(),
+                    event_status_enable: self.event_status_enable,
+                    quirks: self.quirks.clone(),
+                    ..Instrument::default()
+                }
+            }
+            (["*CLS"], false) => self.errors.clear(),
+            (["*ESE"], true) => reply(self.event_status_enable.to_string()),
+            (["*ESE"], false) => match args.trim().parse::<u8>() {
+                Ok(mask) => self.event_status_enable = mask,
+                Err(_) => self.push_error(-222, "Data out of range"),
+            },
+            (["RUN"], false) => self.running = true,
+            (["STOP"], false) => self.running = false,
+            (["SING"], false) => {
+                self.sweep = SWEep::SING;
+                self.running = false;
+            }
+            (["SYST", "ERR"], true) | (["SYST", "ERR", "NEXT"], true) => {
+                if self.errors.is_empty() {
+                    reply("0,\"No error\"".to_string());
+                } else {
+                    let (code, message) = self.errors.remove(0);
+                    reply(format!("{},\"{}\"", code, message));
+                }
+            }
+            (["TRIG", "SWE"], true) => reply(self.sweep.to_string()),
+            (["TRIG", "SWE"], false) => match arg.parse::<SWEep>() {
+                Ok(sweep) => {
+                    self.sweep = sweep;
+                    self.running = sweep != SWEep::SING;
+                }
+                Err(_) => self.push_error(-224, "Illegal parameter value"),
+            },
+            (["TIM", "SCAL"], true) => reply(nr3(self.timebase_scale)),
+            (["TIM", "SCAL"], false) => match number {
+                Ok(scale) if scale > 0.0 => self.timebase_scale = scale,
+                _ => self.push_error(-224, "Illegal parameter value"),
+            },
+            (["ACQ", "MDEP"], true) => reply(match self.memory_depth {
+                Some(depth) => self.integer(depth),
+                None => "AUTO".to_string(),
+            }),
+            (["ACQ", "MDEP"], false) => match (arg.as_str(), number) {
+                ("AUTO", _) => self.memory_depth = None,
+                (_, Ok(depth)) if depth >= 1.0 && depth as u32 <= self.max_memory_depth() => self.memory_depth = Some(depth as u32),
+                _ => self.push_error(-224, "Illegal parameter value"),
+            },
+            (["ACQ", "SRAT"], true) => reply(nr3(self.acquired_depth() as f64 / (self.timebase_scale * 12.0))),
+            ([chan, leaf], _) if chan.starts_with("CHAN") => {
+                let index = match chan[4..].parse::<usize>() {
+                    Ok(n) if (1..=self.analog_channels()).contains(&n) => n - 1,
+                    _ => return self.push_error(-114, "Header suffix out of range"),
+                };
+                let ch = &mut self.channels[index];
+                match (*leaf, query) {
+                    ("DISP", true) => reply(if ch.display { "1" } else { "0" }.to_string()),
+                    ("DISP", false) => match arg.as_str() {
+                        "1" | "ON" => ch.display = true,
+                        "0" | "OFF" => ch.display = false,
+                        _ => self.push_error(-224, "Illegal parameter value"),
+                    },
+                    ("SCAL", true) => reply(nr3(ch.scale)),
+                    ("SCAL", false) => match number {
+                        Ok(scale) if scale > 0.0 => ch.scale = scale,
+                        _ => self.push_error(-224, "Illegal parameter value"),
+                    },
+                    ("OFFS", true) => reply(nr3(ch.offset)),
+                    ("OFFS", false) => match number {
+                        Ok(offset) => ch.offset = offset,
+                        _ => self.push_error(-224, "Illegal parameter value"),
+                    },
+                    _ => self.push_error(-113, "Undefined header"),
+                }
+            }
+            (["WAV", leaf], true) => match *leaf {
+                "SOUR" => reply(self.source.to_string()),
+                "MODE" => reply(self.mode.to_string()),
+                "FORM" => reply(self.format.to_string()),
+                "STAR" => reply(self.integer(self.start)),
+                "STOP" => reply(self.integer(self.stop)),
+                "XINC" => reply(nr3(self.x_increment())),
+                "XOR" => reply(nr3(self.x_origin())),
+                "XREF" => reply("0".to_string()),
+                "YINC" => reply(nr3(self.channel_of(self.source).y_increment())),
+                "YOR" => reply(self.channel_of(self.source).y_origin().to_string()),
+                "YREF" => reply(Y_REFERENCE.to_string()),
+                "PRE" => reply(self.preamble()),
+                "DATA" => self.data(out),
+                _ => self.push_error(-113, "Undefined header"),
+            },
+            (["WAV", leaf], false) => match *leaf {
+                "SOUR" => match arg.parse() {
+                    Ok(source) => self.source = source,
+                    Err(_) => self.push_error(-224, "Illegal parameter value"),
+                },
+                "MODE" => match arg.parse() {
+                    Ok(mode) => self.mode = mode,
+                    Err(_) => self.push_error(-224, "Illegal parameter value"),
+                },
+                "FORM" => match arg.parse() {
+                    Ok(format) => self.format = format,
+                    Err(_) => self.push_error(-224, "Illegal parameter value"),
+                },
+                "STAR" | "STOP" => match args.parse::<u32>() {
+                    Ok(point) if point >= 1 && point <= self.points() => {
+                        if *leaf == "STAR" { self.start = point } else { self.stop = point }
+                    }
+                    _ => self.push_error(-222, "Data out of range"),
+                },
+                _ => self.push_error(-113, "Undefined header"),
+            },
+            _ => self.push_error(-113, "Undefined header"),
+        }
+        if let Some(text) = text {
+            out.extend_from_slice(text.as_bytes());
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Simulator {
+    instrument: Instrument,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    pos: usize,
+}
+
+impl Simulator {
+    pub fn new() -> Simulator {
+        Simulator::default()
+    }
+
+    pub fn with_instrument(instrument: Instrument) -> Simulator {
+        Simulator { instrument, ..Simulator::default() }
+    }
+
+    pub fn instrument(&self) -> &Instrument {
+        &self.instrument
+    }
+
+    pub fn instrument_mut(&mut self) -> &mut Instrument {
+        &mut self.instrument
+    }
+}
+
+impl Write for Simulator {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.input.extend_from_slice(buf);
+        while let Some(end) = self.input.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.input.drain(..=end).collect();
+            if self.pos == self.output.len() {
+                self.output.clear();
+                self.pos = 0;
+            }
+            let message = String::from_utf8_lossy(&line);
+            self.instrument.execute(message.trim_end(), &mut self.output);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for Simulator {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for Simulator {
+    // Reading with nothing queued is what a scope does when it was never asked a
+    // question: it stays silent, reported here as a timeout.
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.output.len() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "simulator has no pending response"));
+        }
+        Ok(&self.output[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.output.len());
+    }
+}
+
+// Speaks the raw socket protocol of the scope's port 5555 on one connection until the
+// peer hangs up. Connections share one instrument, like clients of a real scope do.
+pub fn serve_connection(stream: TcpStream, instrument: &Mutex<Instrument>) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut line = Vec::new();
+    let mut response = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(());
+        }
+        let message = String::from_utf8_lossy(&line);
+        response.clear();
+        instrument.lock().unwrap_or_else(|e| e.into_inner()).execute(message.trim_end(), &mut response);
+        if !response.is_empty() {
+            writer.write_all(&response)?;
+        }
+    }
+}
+
+// Accepts connections until `listener` fails. A connection that ends with an error is
+// dropped; `serve_with` hands those errors to a callback.
+pub fn serve(listener: TcpListener, instrument: Arc<Mutex<Instrument>>) -> io::Result<()> {
+    serve_with(listener, instrument, |_, _| {})
+}
+
+// `serve` that calls `closed` with the peer and the error of each connection that ends
+// with one.
+pub fn serve_with<F>(listener: TcpListener, instrument: Arc<Mutex<Instrument>>, closed: F) -> io::Result<()>
+where
+    F: Fn(Option<SocketAddr>, io::Error) + Send + Sync + 'static,
+{
+    let closed = Arc::new(closed);
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let instrument = Arc::clone(&instrument);
+        let closed = Arc::clone(&closed);
+        thread::spawn(move || {
+            let peer = stream.peer_addr().ok();
+            if let Err(err) = serve_connection(stream, &instrument) {
+                closed(peer, err);
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::Visa;
+
+    fn query(sim: &mut Simulator, command: &str) -> String {
+        sim.write_scip_cmd(format!("{}\n", command).as_bytes()).unwrap();
+        sim.read_result().unwrap()
+    }
+
+    #[test]
+    fn test_header_forms() {
+        let mut sim = Simulator::new();
+        sim.write_scip_cmd(b":waveform:format word\n").unwrap();
+        assert_eq!(query(&mut sim, ":WAV:FORM?"), "WORD");
+        sim.write_scip_cmd(b":TIMebase:MAIN:SCALe 0.0005\n").unwrap();
+        assert_eq!(query(&mut sim, ":TIM:SCAL?"), "5.000000e-04");
+        assert_eq!(query(&mut sim, ":WAV:XOR?;:WAV:YREF?;YOR?"), "-3.000000e-03;127;0");
+        assert_eq!(query(&mut sim, ":SYST:ERR?"), "0,\"No error\"");
+        sim.write_scip_cmd(b":WAV:BOGUS 1\n").unwrap();
+        assert_eq!(query(&mut sim, ":SYSTem:ERRor?"), "-113,\"Undefined header\"");
+    }
+
+    #[test]
+    fn test_data_blocks() {
+        let mut sim = Simulator::new();
+        sim.write_scip_cmd(b":WAV:STAR 1;:WAV:STOP 100;:WAV:DATA?\n").unwrap();
+        let bytes = sim.read_block().unwrap();
+        assert_eq!(bytes.len(), 100);
+        assert_eq!(bytes[0], sim.instrument().sample(Source::CHAN1, 0));
+
+        sim.write_scip_cmd(b":WAV:FORM WORD;:WAV:DATA?\n").unwrap();
+        let words = sim.read_block().unwrap();
+        assert_eq!(words.len(), 200);
+        assert_eq!(words[0], bytes[0]);
+        assert_eq!(words[1], 0);
+
+        sim.write_scip_cmd(b":WAV:FORM ASC;:WAV:STOP 3;:WAV:DATA?\n").unwrap();
+        let text = String::from_utf8(sim.read_block().unwrap()).unwrap();
+        assert_eq!(text.split(',').count(), 3);
+    }
+
+    #[test]
+    fn test_preamble_matches_queries() {
+        let mut sim = Simulator::new();
+        sim.instrument_mut().channels[0].offset = 0.4;
+        let preamble = query(&mut sim, ":WAV:PRE?");
+        let fields: Vec<&str> = preamble.split(',').collect();
+        assert_eq!(fields.len(), 10);
+        assert_eq!(fields[2], "1200");
+        assert_eq!(fields[4], query(&mut sim, ":WAV:XINC?"));
+        assert_eq!(fields[8], "10");
+    }
+
+    #[test]
+    fn test_tcp_raw_chunks() {
+        use crate::command::WAVeformCommand::{get_data, ConvertData};
+        use crate::device::BufStream;
+        use crate::Ds1000z;
+        use std::time::Duration;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let instrument = Arc::new(Mutex::new(Instrument { memory_depth: Some(600000), ..Instrument::default() }));
+        let served = Arc::clone(&instrument);
+        thread::spawn(move || serve(listener, served));
+
+        let device = BufStream::connect(address, Duration::from_secs(10)).unwrap();
+        let mut scope = Ds1000z::new(device).unwrap();
+        scope.trigger().sweep(SWEep::SING).unwrap();
+        let mut convert_data = ConvertData::new();
+        let mut waveform = scope.waveform();
+        waveform.format(Format::BYTE).unwrap();
+        waveform.mode(Mode::RAW).unwrap();
+        get_data(600000, &mut waveform, &mut convert_data).unwrap();
+        assert_eq!(convert_data.count, 600000);
+        let instrument = instrument.lock().unwrap();
+        let y = convert_data.data[599999].y;
+        assert!((y - instrument.voltage(Source::CHAN1, 599999) as f32).abs() <= 0.021);
+    }
+
+    // The full 24 Mpts memory of a single channel in 96 chunks, as the scope's raw socket
+    // delivers it.
+    #[test]
+    fn test_tcp_raw_full_memory() {
+        use crate::command::WAVeformCommand::{get_data, ConvertData};
+        use crate::device::BufStream;
+        use crate::Ds1000z;
+        use std::time::Duration;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let instrument = Arc::new(Mutex::new(Instrument::default()));
+        let served = Arc::clone(&instrument);
+        thread::spawn(move || serve(listener, served));
+
+        let device = BufStream::connect(address, Duration::from_secs(10)).unwrap();
+        let mut scope = Ds1000z::new(device).unwrap();
+        scope.trigger().sweep(SWEep::SING).unwrap();
+        let mut convert_data = ConvertData::new();
+        let mut waveform = scope.waveform();
+        waveform.format(Format::BYTE).unwrap();
+        waveform.mode(Mode::RAW).unwrap();
+        get_data(MEMORY_POINTS, &mut waveform, &mut convert_data).unwrap();
+        assert_eq!(convert_data.count, MEMORY_POINTS);
+        let instrument = instrument.lock().unwrap();
+        for i in (0..MEMORY_POINTS).step_by(999_983).chain([MEMORY_POINTS - 1]) {
+            let y = convert_data.data[i as usize].y;
+            assert!((y - instrument.voltage(Source::CHAN1, i) as f32).abs() <= 0.021, "point {}", i);
+        }
+    }
+}
@@ -0,0 +1,434 @@
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::Instant;
+use crate::block::BlockError;
+use crate::device::Visa;
+use crate::error::{Error, Result};
+
+// A transcript is a text file with one exchange per line:
+//
+//     <microseconds since start> <kind> <data>
+//
+// where kind is `W` (command written), `R` (response line), `B` (block payload as hex),
+// `T` (read timed out) or `X` (read failed). Commands and responses are escaped so
+// that every entry stays on one line. A failure starts with the error it was, so that
+// replay returns the same `Error` variant:
+//
+//     io <kind> <message>              Error::IoError
+//     protocol <message>               Error::ProtocolError
+//     block-header <hex>               BlockError::InvalidHeader
+//     block-truncated <expected> <received>
+//     block-terminator <hex or ->      BlockError::MissingTerminator
+//
+// Anything else (or a transcript from before failures were tagged) replays as an
+// `Error::IoError` with the recorded message.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    Write(String),
+    Response(String),
+    Block(Vec<u8>),
+    Timeout(String),
+    Failure(String),
+}
+
+impl Entry {
+    fn kind(&self) -> char {
+        match self {
+            Entry::Write(_) => 'W',
+            Entry::Response(_) => 'R',
+            Entry::Block(_) => 'B',
+            Entry::Timeout(_) => 'T',
+            Entry::Failure(_) => 'X',
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Entry::Block(data) => format!("a block of {} bytes", data.len()),
+            Entry::Write(text) => format!("command {:?}", text),
+            Entry::Response(text) => format!("response {:?}", text),
+            Entry::Timeout(_) => "a timeout".to_string(),
+            Entry::Failure(text) => format!("failure {:?}", text),
+        }
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\x{:02x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(text: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next()? {
+            '\\' => unescaped.push('\\'),
+            'n' => unescaped.push('\n'),
+            'r' => unescaped.push('\r'),
+            'x' => {
+                let hex: String = chars.by_ref().take(2).collect();
+                unescaped.push(char::from(u8::from_str_radix(&hex, 16).ok()?));
+            }
+            _ => return None,
+        }
+    }
+    Some(unescaped)
+}
+
+fn to_hex(data: &[u8]) -> String {
+    let mut hex = String::with_capacity(data.len() * 2);
+    for byte in data {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+// The io::ErrorKinds a transport read can fail with.
+const IO_KINDS: &[(io::ErrorKind, &str)] = &[
+    (io::ErrorKind::UnexpectedEof, "unexpected-eof"),
+    (io::ErrorKind::ConnectionReset, "connection-reset"),
+    (io::ErrorKind::ConnectionAborted, "connection-aborted"),
+    (io::ErrorKind::NotConnected, "not-connected"),
+    (io::ErrorKind::BrokenPipe, "broken-pipe"),
+    (io::ErrorKind::InvalidData, "invalid-data"),
+];
+
+fn encode_failure(err: &Error) -> String {
+    match err {
+        Error::IoError(err) => {
+            let kind = IO_KINDS.iter().find(|(kind, _)| *kind == err.kind()).map_or("other", |(_, name)| name);
+            format!("io {} {}", kind, err)
+        }
+        Error::ProtocolError(message) => format!("protocol {}", message),
+        Error::BlockError(BlockError::InvalidHeader(header)) => format!("block-header {}", to_hex(header)),
+        Error::BlockError(BlockError::Truncated { expected, received }) => format!("block-truncated {} {}", expected, received),
+        Error::BlockError(BlockError::MissingTerminator(byte)) => {
+            format!("block-terminator {}", byte.map_or("-".to_string(), |byte| to_hex(&[byte])))
+        }
+        err => err.to_string(),
+    }
+}
+
+fn decode_failure(failure: &str) -> Error {
+    let (tag, rest) = failure.split_once(' ').unwrap_or((failure, ""));
+    let decoded = match tag {
+        "io" => rest.split_once(' ').map(|(name, message)| {
+            let kind = IO_KINDS.iter().find(|(_, known)| *known == name).map_or(io::ErrorKind::Other, |(kind, _)| *kind);
+            Error::IoError(io::Error::new(kind, message))
+        }),
+        "protocol" => Some(Error::ProtocolError(rest.to_string())),
+        "block-header" => from_hex(rest).map(|header| Error::BlockError(BlockError::InvalidHeader(header))),
+        "block-truncated" => rest.split_once(' ').and_then(|(expected, received)| {
+            Some(Error::BlockError(BlockError::Truncated { expected: expected.parse().ok()?, received: received.parse().ok()? }))
+        }),
+        "block-terminator" if rest == "-" => Some(Error::BlockError(BlockError::MissingTerminator(None))),
+        "block-terminator" => from_hex(rest).filter(|byte| byte.len() == 1).map(|byte| Error::BlockError(BlockError::MissingTerminator(Some(byte[0])))),
+        _ => None,
+    };
+    decoded.unwrap_or_else(|| Error::IoError(io::Error::other(failure.to_string())))
+}
+
+pub fn parse_entry(line: &str) -> Result<Entry> {
+    let invalid = || Error::ProtocolError(format!("invalid transcript line {:?}", line));
+    let mut fields = line.splitn(3, ' ');
+    let _timestamp: u64 = fields.next().and_then(|t| t.parse().ok()).ok_or_else(invalid)?;
+    let kind = fields.next().ok_or_else(invalid)?;
+    let data = fields.next().unwrap_or("");
+    let text = || unescape(data).ok_or_else(invalid);
+    Ok(match kind {
+        "W" => Entry::Write(text()?),
+        "R" => Entry::Response(text()?),
+        "B" => Entry::Block(from_hex(data).ok_or_else(invalid)?),
+        "T" => Entry::Timeout(text()?),
+        "X" => Entry::Failure(text()?),
+        _ => return Err(invalid()),
+    })
+}
+
+// Forwards everything to `device` and logs each exchange to `log`.
+#[derive(Debug)]
+pub struct Recorder<V: Visa, W: Write> {
+    device: V,
+    log: W,
+    start: Instant,
+}
+
+impl<V: Visa> Recorder<V, File> {
+    pub fn create<P: AsRef<Path>>(device: V, path: P) -> Result<Recorder<V, File>> {
+        Ok(Recorder::new(device, File::create(path)?))
+    }
+}
+
+impl<V: Visa, W: Write> Recorder<V, W> {
+    pub fn new(device: V, log: W) -> Recorder<V, W> {
+        Recorder { device, log, start: Instant::now() }
+    }
+
+    pub fn get_ref(&self) -> &V {
+        &self.device
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        &mut self.device
+    }
+
+    pub fn into_inner(self) -> (V, W) {
+        (self.device, self.log)
+    }
+
+    fn record(&mut self, entry: &Entry) -> Result<()> {
+        let data = match entry {
+            Entry::Block(data) => to_hex(data),
+            Entry::Write(text) | Entry::Response(text) | Entry::Timeout(text) | Entry::Failure(text) => escape(text),
+        };
+        let micros = self.start.elapsed().as_micros();
+        writeln!(self.log, "{} {} {}", micros, entry.kind(), data)?;
+        self.log.flush()?;
+        Ok(())
+    }
+
+    fn record_read<T>(&mut self, result: Result<T>, entry: impl FnOnce(&T) -> Entry) -> Result<T> {
+        let logged = match &result {
+            Ok(value) => entry(value),
+            Err(Error::Timeout(err)) => Entry::Timeout(err.to_string()),
+            Err(err) => Entry::Failure(encode_failure(err)),
+        };
+        self.record(&logged)?;
+        result
+    }
+}
+
+impl<V: Visa, W: Write> Visa for Recorder<V, W> {
+    fn write_scip_cmd(&mut self, buf: &[u8]) -> Result<()> {
+        let command = String::from_utf8_lossy(buf);
+        self.record(&Entry::Write(command.trim_end_matches('\n').to_string()))?;
+        self.device.write_scip_cmd(buf)
+    }
+
+    fn read_result(&mut self) -> Result<String> {
+        let result = self.device.read_result();
+        self.record_read(result, |text| Entry::Response(text.clone()))
+    }
+
+    fn read_result2(&mut self) -> Result<String> {
+        let result = self.device.read_result2();
+        self.record_read(result, |text| Entry::Response(text.clone()))
+    }
+
+    fn read_block(&mut self) -> Result<Vec<u8>> {
+        let result = self.device.read_block();
+        self.record_read(result, |data| Entry::Block(data.clone()))
+    }
+
+    // Not recorded: the replayed block has nothing after it.
+    fn discard(&mut self, len: usize) -> Result<()> {
+        self.device.discard(len)
+    }
+}
+
+// Serves a recorded transcript back. Commands must be sent in the recorded order with
+// the recorded text; any divergence is reported as a protocol error and leaves the
+// entry in place, so the transcript stays where the recording was.
+#[derive(Debug, Clone)]
+pub struct Replay {
+    entries: VecDeque<Entry>,
+}
+
+impl Replay {
+    pub fn new(entries: Vec<Entry>) -> Replay {
+        Replay { entries: entries.into() }
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Replay> {
+        Replay::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<Replay> {
+        let mut entries = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if !line.is_empty() {
+                entries.push(parse_entry(&line)?);
+            }
+        }
+        Ok(Replay::new(entries))
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Removes the next entry if `take` accepts it; a rejected entry is put back.
+    fn take<T>(&mut self, expected: &str, take: impl FnOnce(Entry) -> std::result::Result<T, (Entry, Error)>) -> Result<T> {
+        let entry = self.entries.pop_front()
+            .ok_or_else(|| Error::ProtocolError(format!("replay exhausted, expected {}", expected)))?;
+        take(entry).map_err(|(entry, err)| {
+            self.entries.push_front(entry);
+            err
+        })
+    }
+
+    // A recorded timeout or failure is returned as the error it was.
+    fn read<T>(&mut self, what: &str, take: impl FnOnce(Entry) -> std::result::Result<T, Entry>) -> Result<T> {
+        self.take("a read", |entry| match entry {
+            Entry::Timeout(message) => Ok(Err(io::Error::new(io::ErrorKind::TimedOut, message).into())),
+            Entry::Failure(failure) => Ok(Err(decode_failure(&failure))),
+            entry => take(entry).map(Ok).map_err(|entry| {
+                let err = Error::ProtocolError(format!("replay expected {}, got {}", entry.describe(), what));
+                (entry, err)
+            }),
+        })?
+    }
+}
+
+impl Visa for Replay {
+    fn write_scip_cmd(&mut self, buf: &[u8]) -> Result<()> {
+        let command = String::from_utf8_lossy(buf);
+        let command = command.trim_end_matches('\n');
+        self.take(&format!("command {:?}", command), |entry| match entry {
+            Entry::Write(ref recorded) if recorded == command => Ok(()),
+            entry => {
+                let err = Error::ProtocolError(format!("replay expected {}, got command {:?}", entry.describe(), command));
+                Err((entry, err))
+            }
+        })
+    }
+
+    fn read_result(&mut self) -> Result<String> {
+        self.read("a response read", |entry| match entry {
+            Entry::Response(text) => Ok(text),
+            entry => Err(entry),
+        })
+    }
+
+    fn read_result2(&mut self) -> Result<String> {
+        self.read_result()
+    }
+
+    fn read_block(&mut self) -> Result<Vec<u8>> {
+        self.read("a block read", |entry| match entry {
+            Entry::Block(data) => Ok(data),
+            entry => Err(entry),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::TRIGgerCommand::SWEep;
+    use crate::command::WAVeformCommand::{get_data, ConvertData, Format, Mode};
+    use crate::simulator::{Instrument, Simulator};
+    use crate::Ds1000z;
+
+    fn acquire<V: Visa>(device: V) -> (ConvertData, V) {
+        let mut scope = Ds1000z::new(device).unwrap();
+        scope.trigger().sweep(SWEep::SING).unwrap();
+        let mut convert_data = ConvertData::new();
+        {
+            let mut waveform = scope.waveform();
+            waveform.format(Format::BYTE).unwrap();
+            waveform.mode(Mode::RAW).unwrap();
+            get_data(3000, &mut waveform, &mut convert_data).unwrap();
+        }
+        (convert_data, scope.into_inner())
+    }
+
+    #[test]
+    fn test_escape_roundtrip() {
+        for text in ["plain", "a\\b\nc\r", "tab\there \u{7f}"] {
+            assert_eq!(unescape(&escape(text)).unwrap(), text);
+        }
+        assert_eq!(from_hex(&to_hex(&[0, 0x7f, 0xff])).unwrap(), [0, 0x7f, 0xff]);
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let simulator = Simulator::with_instrument(Instrument { memory_depth: Some(3000), ..Instrument::default() });
+        let (recorded, recorder) = acquire(Recorder::new(simulator, Vec::new()));
+        let (_, log) = recorder.into_inner();
+
+        let replay = Replay::from_reader(&log[..]).unwrap();
+        let (replayed, replay) = acquire(replay);
+        assert!(replay.is_finished());
+        assert_eq!(replayed.count, recorded.count);
+        assert_eq!(replayed.data[..3000], recorded.data[..3000]);
+    }
+
+    #[test]
+    fn test_replay_rejects_other_commands() {
+        let mut replay = Replay::from_reader(&b"0 W :TRIG:SWE?\n5 R AUTO\n9 T timed out\n"[..]).unwrap();
+        match replay.write_scip_cmd(b":WAV:MODE?\n") {
+            Err(Error::ProtocolError(_)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        // the mismatch did not use up the recorded command
+        assert!(matches!(replay.read_result(), Err(Error::ProtocolError(_))));
+        replay.write_scip_cmd(b":TRIG:SWE?\n").unwrap();
+        assert!(matches!(replay.read_block(), Err(Error::ProtocolError(_))));
+        assert_eq!(replay.read_result().unwrap(), "AUTO");
+        assert!(matches!(replay.read_result(), Err(Error::Timeout(_))));
+        assert!(matches!(replay.read_block(), Err(Error::ProtocolError(_))));
+    }
+
+    #[test]
+    fn test_replay_failure_variants() {
+        let failures = [
+            Error::BlockError(BlockError::Truncated { expected: 250000, received: 1200 }),
+            Error::BlockError(BlockError::MissingTerminator(Some(b'#'))),
+            Error::BlockError(BlockError::MissingTerminator(None)),
+            Error::BlockError(BlockError::InvalidHeader(b"1.0".to_vec())),
+            Error::ProtocolError("response is not valid UTF-8".to_string()),
+            Error::IoError(io::Error::new(io::ErrorKind::ConnectionReset, "reset by peer")),
+        ];
+        let mut log = Vec::new();
+        let mut recorder = Recorder::new(Simulator::new(), &mut log);
+        for failure in failures {
+            recorder.record_read::<()>(Err(failure), |_| unreachable!()).unwrap_err();
+        }
+        let mut replay = Replay::from_reader(&log[..]).unwrap();
+        assert!(matches!(replay.read_block(), Err(Error::BlockError(BlockError::Truncated { expected: 250000, received: 1200 }))));
+        assert!(matches!(replay.read_block(), Err(Error::BlockError(BlockError::MissingTerminator(Some(b'#'))))));
+        assert!(matches!(replay.read_block(), Err(Error::BlockError(BlockError::MissingTerminator(None)))));
+        assert!(matches!(replay.read_block(), Err(Error::BlockError(BlockError::InvalidHeader(header))) if header == b"1.0"));
+        assert!(matches!(replay.read_result(), Err(Error::ProtocolError(message)) if message == "response is not valid UTF-8"));
+        match replay.read_result() {
+            Err(Error::IoError(err)) => assert_eq!((err.kind(), err.to_string()), (io::ErrorKind::ConnectionReset, "reset by peer".to_string())),
+            other => panic!("unexpected {:?}", other),
+        }
+        // untagged failures of older transcripts
+        let mut replay = Replay::from_reader(&b"0 X connection lost\n"[..]).unwrap();
+        assert!(matches!(replay.read_result(), Err(Error::IoError(err)) if err.to_string() == "connection lost"));
+    }
+}
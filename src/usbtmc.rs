@@ -0,0 +1,344 @@
+use std::io;
+use crate::block;
+use crate::device::{self, Visa};
+use crate::error::{Error, Result};
+
+// USBTMC (USB Test & Measurement Class) bulk transfer framing. Every transfer starts with
+// a 12 byte header:
+//
+//     MsgID, bTag, !bTag, 0, TransferSize (u32 LE), bmTransferAttributes, TermChar, 0, 0
+//
+// followed by the payload, padded to a multiple of four bytes. A command is sent as
+// DEV_DEP_MSG_OUT transfers, the last one flagged EOM; a response is requested with
+// REQUEST_DEV_DEP_MSG_IN and arrives as DEV_DEP_MSG_IN transfers until one carries EOM.
+// The USB side is left to a `BulkEndpoint`, so a libusb handle or a raw device node can
+// be plugged in.
+
+pub const HEADER_SIZE: usize = 12;
+pub const DEV_DEP_MSG_OUT: u8 = 1;
+pub const REQUEST_DEV_DEP_MSG_IN: u8 = 2;
+pub const DEV_DEP_MSG_IN: u8 = 2;
+pub const ATTR_EOM: u8 = 0x01;
+pub const ATTR_TERM_CHAR: u8 = 0x02;
+
+const DEFAULT_TRANSFER_SIZE: u32 = 1024 * 1024;
+
+// The bulk-out and bulk-in endpoints of a USBTMC interface. `bulk_in` returns the next
+// transfer from the device, or as much of it as fits in `buf`; the remainder is returned
+// by the following calls. Timeouts are the endpoint's business and are reported as
+// `io::ErrorKind::TimedOut`.
+pub trait BulkEndpoint {
+    fn bulk_out(&mut self, data: &[u8]) -> io::Result<()>;
+    fn bulk_in(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Header {
+    pub msg_id: u8,
+    pub tag: u8,
+    pub transfer_size: u32,
+    pub attributes: u8,
+    pub term_char: u8,
+}
+
+impl Header {
+    pub fn dev_dep_msg_out(tag: u8, transfer_size: u32, eom: bool) -> Header {
+        Header { msg_id: DEV_DEP_MSG_OUT, tag, transfer_size, attributes: if eom { ATTR_EOM } else { 0 }, term_char: 0 }
+    }
+
+    pub fn request_dev_dep_msg_in(tag: u8, transfer_size: u32, term_char: Option<u8>) -> Header {
+        Header {
+            msg_id: REQUEST_DEV_DEP_MSG_IN,
+            tag,
+            transfer_size,
+            attributes: if term_char.is_some() { ATTR_TERM_CHAR } else { 0 },
+            term_char: term_char.unwrap_or(0),
+        }
+    }
+
+    pub fn eom(&self) -> bool {
+        self.attributes & ATTR_EOM != 0
+    }
+
+    pub fn encode(&self) -> [u8; HEADER_SIZE] {
+        let size = self.transfer_size.to_le_bytes();
+        [self.msg_id, self.tag, !self.tag, 0, size[0], size[1], size[2], size[3], self.attributes, self.term_char, 0, 0]
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Header> {
+        let header: &[u8; HEADER_SIZE] = buf.get(..HEADER_SIZE).and_then(|b| b.try_into().ok())
+            .ok_or_else(|| Error::ProtocolError(format!("USBTMC header needs {} bytes, got {}", HEADER_SIZE, buf.len())))?;
+        if header[2] != !header[1] {
+            return Err(Error::ProtocolError(format!("USBTMC header with bTag {} and bTagInverse {}", header[1], header[2])));
+        }
+        Ok(Header {
+            msg_id: header[0],
+            tag: header[1],
+            transfer_size: u32::from_le_bytes([header[4], header[5], header[6], header[7]]),
+            attributes: header[8],
+            term_char: header[9],
+        })
+    }
+}
+
+// Header, payload and alignment padding of one bulk-out transfer.
+pub fn encode_transfer(header: &Header, payload: &[u8]) -> Vec<u8> {
+    let mut transfer = Vec::with_capacity(HEADER_SIZE + payload.len() + 3);
+    transfer.extend_from_slice(&header.encode());
+    transfer.extend_from_slice(payload);
+    transfer.resize(transfer.len().next_multiple_of(4), 0);
+    transfer
+}
+
+#[derive(Debug)]
+pub struct Usbtmc<E: BulkEndpoint> {
+    endpoint: E,
+    tag: u8,
+    transfer_size: u32,
+}
+
+impl<E: BulkEndpoint> Usbtmc<E> {
+    pub fn new(endpoint: E) -> Usbtmc<E> {
+        Usbtmc { endpoint, tag: 0, transfer_size: DEFAULT_TRANSFER_SIZE }
+    }
+
+    // Largest payload per transfer, in both directions.
+    pub fn set_transfer_size(&mut self, transfer_size: u32) {
+        self.transfer_size = transfer_size.max(1);
+    }
+
+    pub fn get_ref(&self) -> &E {
+        &self.endpoint
+    }
+
+    pub fn get_mut(&mut self) -> &mut E {
+        &mut self.endpoint
+    }
+
+    pub fn into_inner(self) -> E {
+        self.endpoint
+    }
+
+    // bTag runs 1..=255, zero is reserved
+    fn next_tag(&mut self) -> u8 {
+        self.tag = self.tag % 255 + 1;
+        self.tag
+    }
+
+    fn read_transfer(&mut self) -> Result<(Vec<u8>, bool)> {
+        let tag = self.next_tag();
+        let request = Header::request_dev_dep_msg_in(tag, self.transfer_size, None);
+        self.endpoint.bulk_out(&encode_transfer(&request, &[]))?;
+
+        let mut buf = vec![0u8; HEADER_SIZE + self.transfer_size as usize + 3];
+        let mut received = 0;
+        let mut header = None;
+        loop {
+            let n = self.endpoint.bulk_in(&mut buf[received..])?;
+            if n == 0 {
+                return Err(Error::ProtocolError(format!("USBTMC transfer ended after {} bytes", received)));
+            }
+            received += n;
+            if header.is_none() && received >= HEADER_SIZE {
+                let decoded = Header::decode(&buf)?;
+                if decoded.msg_id != DEV_DEP_MSG_IN || decoded.tag != tag {
+                    return Err(Error::ProtocolError(format!(
+                        "expected DEV_DEP_MSG_IN with bTag {}, got MsgID {} with bTag {}", tag, decoded.msg_id, decoded.tag
+                    )));
+                }
+                if decoded.transfer_size > self.transfer_size {
+                    return Err(Error::ProtocolError(format!(
+                        "device sent {} bytes, {} were requested", decoded.transfer_size, self.transfer_size
+                    )));
+                }
+                header = Some(decoded);
+            }
+            // the alignment padding is read too, or it would be taken for the start
+            // of the next transfer
+            if let Some(header) = header {
+                let end = HEADER_SIZE + header.transfer_size as usize;
+                if received >= end.next_multiple_of(4) {
+                    buf.truncate(end);
+                    buf.drain(..HEADER_SIZE);
+                    return Ok((buf, header.eom()));
+                }
+            }
+        }
+    }
+
+    // Reads one complete response message, up to the transfer flagged EOM.
+    pub fn read_message(&mut self) -> Result<Vec<u8>> {
+        let mut message = Vec::new();
+        loop {
+            let (payload, eom) = self.read_transfer()?;
+            message.extend_from_slice(&payload);
+            if eom {
+                return Ok(message);
+            }
+        }
+    }
+}
+
+impl<E: BulkEndpoint> Visa for Usbtmc<E> {
+    fn write_scip_cmd(&mut self, buf: &[u8]) -> Result<()> {
+        let mut chunks = buf.chunks(self.transfer_size as usize).peekable();
+        while let Some(chunk) = chunks.next() {
+            let header = Header::dev_dep_msg_out(self.next_tag(), chunk.len() as u32, chunks.peek().is_none());
+            self.endpoint.bulk_out(&encode_transfer(&header, chunk))?;
+        }
+        Ok(())
+    }
+
+    fn read_result(&mut self) -> Result<String> {
+        device::response_text(self.read_message()?)
+    }
+
+    fn read_result2(&mut self) -> Result<String> {
+        self.read_result()
+    }
+
+    fn read_block(&mut self) -> Result<Vec<u8>> {
+        Ok(block::from_message(self.read_message()?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::io::{BufRead, Write};
+    use crate::command::TRIGgerCommand::SWEep;
+    use crate::command::WAVeformCommand::{get_data, ConvertData, Format, Mode};
+    use crate::simulator::{Instrument, Simulator};
+    use crate::Ds1000z;
+
+    // A USBTMC function in front of the simulator. Bulk-in transfers are handed out in
+    // 64 byte packets; `tag_offset` makes the device answer with the wrong bTag.
+    struct SimulatedEndpoint {
+        simulator: Simulator,
+        bulk_in: VecDeque<Vec<u8>>,
+        tags: Vec<u8>,
+        tag_offset: u8,
+    }
+
+    impl SimulatedEndpoint {
+        fn new(instrument: Instrument) -> SimulatedEndpoint {
+            SimulatedEndpoint { simulator: Simulator::with_instrument(instrument), bulk_in: VecDeque::new(), tags: Vec::new(), tag_offset: 0 }
+        }
+    }
+
+    impl BulkEndpoint for SimulatedEndpoint {
+        fn bulk_out(&mut self, data: &[u8]) -> io::Result<()> {
+            assert!(data.len().is_multiple_of(4));
+            let header = Header::decode(data).unwrap();
+            self.tags.push(header.tag);
+            match header.msg_id {
+                DEV_DEP_MSG_OUT => self.simulator.write_all(&data[HEADER_SIZE..HEADER_SIZE + header.transfer_size as usize]),
+                REQUEST_DEV_DEP_MSG_IN => {
+                    // nothing to send: the device NAKs until the host gives up
+                    let Ok(pending) = self.simulator.fill_buf() else { return Ok(()) };
+                    let n = pending.len().min(header.transfer_size as usize);
+                    let payload = pending[..n].to_vec();
+                    self.simulator.consume(n);
+                    let eom = self.simulator.fill_buf().is_err();
+                    let mut response = Header { msg_id: DEV_DEP_MSG_IN, attributes: if eom { ATTR_EOM } else { 0 }, ..header };
+                    response.transfer_size = n as u32;
+                    response.tag = header.tag.wrapping_add(self.tag_offset);
+                    self.bulk_in.push_back(encode_transfer(&response, &payload));
+                    Ok(())
+                }
+                id => panic!("unexpected MsgID {}", id),
+            }
+        }
+
+        fn bulk_in(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let transfer = self.bulk_in.front_mut().ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "bulk-in timed out"))?;
+            let n = transfer.len().min(buf.len()).min(64);
+            buf[..n].copy_from_slice(&transfer[..n]);
+            transfer.drain(..n);
+            if transfer.is_empty() {
+                self.bulk_in.pop_front();
+            }
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_header_encoding() {
+        let header = Header::dev_dep_msg_out(7, 5, true);
+        assert_eq!(header.encode(), [1, 7, 0xf8, 0, 5, 0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(Header::decode(&header.encode()).unwrap(), header);
+        let transfer = encode_transfer(&header, b"*IDN?");
+        assert_eq!(transfer.len(), 20);
+        assert_eq!(&transfer[12..], b"*IDN?\0\0\0");
+        assert_eq!(Header::request_dev_dep_msg_in(1, 1024, Some(b'\n')).encode(), [2, 1, 0xfe, 0, 0, 4, 0, 0, 2, b'\n', 0, 0]);
+
+        let mut corrupt = header.encode();
+        corrupt[2] = 0;
+        assert!(matches!(Header::decode(&corrupt), Err(Error::ProtocolError(_))));
+        assert!(matches!(Header::decode(&corrupt[..8]), Err(Error::ProtocolError(_))));
+    }
+
+    #[test]
+    fn test_waveform_over_usbtmc() {
+        let mut device = Usbtmc::new(SimulatedEndpoint::new(Instrument { memory_depth: Some(3000), ..Instrument::default() }));
+        device.set_transfer_size(1000);
+        let mut scope = Ds1000z::new(device).unwrap();
+        scope.trigger().sweep(SWEep::SING).unwrap();
+        let mut convert_data = ConvertData::new();
+        let mut waveform = scope.waveform();
+        waveform.format(Format::BYTE).unwrap();
+        waveform.mode(Mode::RAW).unwrap();
+        get_data(3000, &mut waveform, &mut convert_data).unwrap();
+        assert_eq!(convert_data.count, 3000);
+        assert!(matches!(scope.device().read_result(), Err(Error::Timeout(_))));
+    }
+
+    // Hands out scripted bulk-in returns, one per call.
+    struct ScriptedEndpoint {
+        bulk_in: VecDeque<Vec<u8>>,
+    }
+
+    impl BulkEndpoint for ScriptedEndpoint {
+        fn bulk_out(&mut self, _data: &[u8]) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn bulk_in(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let data = self.bulk_in.pop_front().ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "bulk-in timed out"))?;
+            buf[..data.len()].copy_from_slice(&data);
+            Ok(data.len())
+        }
+    }
+
+    #[test]
+    fn test_padding_in_separate_bulk_in() {
+        let mut bulk_in = VecDeque::new();
+        for (tag, payload) in [(1, b"1\n"), (2, b"2\n")] {
+            let mut transfer = encode_transfer(&Header { msg_id: DEV_DEP_MSG_IN, tag, transfer_size: 2, attributes: ATTR_EOM, term_char: 0 }, payload);
+            let padding = transfer.split_off(HEADER_SIZE + payload.len());
+            assert_eq!(padding, [0, 0]);
+            bulk_in.extend([transfer, padding]);
+        }
+        let mut device = Usbtmc::new(ScriptedEndpoint { bulk_in });
+        assert_eq!(device.read_result().unwrap(), "1");
+        assert_eq!(device.read_result().unwrap(), "2");
+        assert!(device.get_ref().bulk_in.is_empty());
+    }
+
+    #[test]
+    fn test_tags() {
+        let mut device = Usbtmc::new(SimulatedEndpoint::new(Instrument::default()));
+        device.set_transfer_size(4);
+        device.tag = 250;
+        device.write_scip_cmd(b"*IDN?\n").unwrap();
+        assert!(device.read_result().unwrap().starts_with("RIGOL TECHNOLOGIES"));
+        let tags = &device.get_ref().tags;
+        assert_eq!(tags[..8], [251, 252, 253, 254, 255, 1, 2, 3]);
+        assert!(!tags.contains(&0));
+
+        device.get_mut().tag_offset = 1;
+        device.write_scip_cmd(b"*OPC?\n").unwrap();
+        assert!(matches!(device.read_result(), Err(Error::ProtocolError(_))));
+    }
+}
@@ -0,0 +1,365 @@
+use std::net::TcpStream;
+use std::time::Duration;
+use crate::block;
+use crate::device::{self, Visa};
+use crate::error::{Error, Result};
+use crate::resource::{self, Timeouts};
+use crate::rpc::{self, RpcClient, XdrReader, XdrWriter};
+
+// VXI-11 client for the scope's `INSTR` endpoint: the portmapper tells which port the
+// DEVICE_CORE program listens on, and every SCPI message then travels as an RPC call
+// with explicit END marking, so responses never depend on a terminator or a timeout.
+
+pub const DEVICE_CORE_PROG: u32 = 0x0607af;
+pub const DEVICE_CORE_VERS: u32 = 1;
+
+pub const CREATE_LINK: u32 = 10;
+pub const DEVICE_WRITE: u32 = 11;
+pub const DEVICE_READ: u32 = 12;
+pub const DEVICE_CLEAR: u32 = 15;
+pub const DEVICE_LOCK: u32 = 18;
+pub const DEVICE_UNLOCK: u32 = 19;
+pub const DESTROY_LINK: u32 = 23;
+
+pub const FLAG_WAITLOCK: u32 = 0x01;
+pub const FLAG_END: u32 = 0x08;
+pub const REASON_END: u32 = 0x04;
+
+const READ_REQUEST_SIZE: u32 = 1024 * 1024;
+const SOCKET_MARGIN: Duration = Duration::from_secs(2);
+
+fn error_name(code: u32) -> &'static str {
+    match code {
+        1 => "syntax error",
+        3 => "device not accessible",
+        4 => "invalid link identifier",
+        5 => "parameter error",
+        6 => "channel not established",
+        8 => "operation not supported",
+        9 => "out of resources",
+        11 => "device locked by another link",
+        12 => "no lock held by this link",
+        15 => "I/O timeout",
+        17 => "I/O error",
+        21 => "invalid address",
+        23 => "abort",
+        29 => "channel already established",
+        _ => "unknown error",
+    }
+}
+
+fn check(code: u32) -> Result<()> {
+    match code {
+        0 => Ok(()),
+        15 => Err(Error::Timeout(std::io::Error::new(std::io::ErrorKind::TimedOut, "VXI-11 I/O timeout"))),
+        code => Err(Error::ProtocolError(format!("VXI-11 error {} ({})", code, error_name(code)))),
+    }
+}
+
+#[derive(Debug)]
+pub struct Vxi11 {
+    client: RpcClient,
+    link: i32,
+    max_recv_size: u32,
+    device: String,
+    timeouts: Timeouts,
+    lock_timeout: Duration,
+}
+
+impl Vxi11 {
+    pub fn connect(host: &str, device: &str, timeouts: Timeouts) -> Result<Vxi11> {
+        Vxi11::connect_via(host, rpc::PORTMAP_PORT, device, timeouts)
+    }
+
+    // Like `connect`, with the portmapper listening on `portmapper_port`.
+    pub fn connect_via(host: &str, portmapper_port: u16, device: &str, timeouts: Timeouts) -> Result<Vxi11> {
+        let portmapper = resource::connect(host, portmapper_port, timeouts)?;
+        let port = rpc::getport(portmapper, DEVICE_CORE_PROG, DEVICE_CORE_VERS)?;
+        Vxi11::create_link(resource::connect(host, port, timeouts)?, device, timeouts)
+    }
+
+    pub fn create_link(stream: TcpStream, device: &str, timeouts: Timeouts) -> Result<Vxi11> {
+        stream.set_read_timeout(Some(timeouts.read + SOCKET_MARGIN))?;
+        let mut client = RpcClient::new(stream, DEVICE_CORE_PROG, DEVICE_CORE_VERS);
+        let mut args = XdrWriter::new();
+        args.i32(std::process::id() as i32).bool(false).u32(0).string(device);
+        let results = client.call(CREATE_LINK, args.bytes())?;
+        let mut reply = XdrReader::new(&results);
+        check(reply.u32()?)?;
+        let link = reply.i32()?;
+        let _abort_port = reply.u32()?;
+        let max_recv_size = reply.u32()?.max(1);
+        Ok(Vxi11 { client, link, max_recv_size, device: device.to_string(), timeouts, lock_timeout: Duration::from_secs(5) })
+    }
+
+    fn io_timeout_ms(&self) -> u32 {
+        self.timeouts.read.as_millis().min(u32::MAX as u128) as u32
+    }
+
+    fn lock_timeout_ms(&self) -> u32 {
+        self.lock_timeout.as_millis().min(u32::MAX as u128) as u32
+    }
+
+    pub fn set_lock_timeout(&mut self, lock_timeout: Duration) {
+        self.lock_timeout = lock_timeout;
+    }
+
+    fn call_generic(&mut self, procedure: u32, args: &[u8]) -> Result<()> {
+        let results = self.client.call(procedure, args)?;
+        check(XdrReader::new(&results).u32()?)
+    }
+
+    pub fn device_write(&mut self, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let mut chunks = data.chunks(self.max_recv_size as usize).peekable();
+        while let Some(chunk) = chunks.next() {
+            let flags = if chunks.peek().is_none() { FLAG_END } else { 0 };
+            let mut args = XdrWriter::new();
+            args.i32(self.link).u32(self.io_timeout_ms()).u32(self.lock_timeout_ms()).u32(flags).opaque(chunk);
+            let results = self.client.call(DEVICE_WRITE, args.bytes())?;
+            let mut reply = XdrReader::new(&results);
+            check(reply.u32()?)?;
+            let written = reply.u32()? as usize;
+            if written != chunk.len() {
+                return Err(Error::ProtocolError(format!("device accepted {} of {} bytes", written, chunk.len())));
+            }
+        }
+        Ok(())
+    }
+
+    // Reads one complete response message, up to the END indicator.
+    pub fn device_read(&mut self) -> Result<Vec<u8>> {
+        let mut message = Vec::new();
+        loop {
+            let mut args = XdrWriter::new();
+            args.i32(self.link).u32(READ_REQUEST_SIZE).u32(self.io_timeout_ms()).u32(self.lock_timeout_ms()).u32(0).u32(0);
+            let results = self.client.call(DEVICE_READ, args.bytes())?;
+            let mut reply = XdrReader::new(&results);
+            check(reply.u32()?)?;
+            let reason = reply.u32()?;
+            message.extend_from_slice(reply.opaque()?);
+            if reason & REASON_END != 0 {
+                return Ok(message);
+            }
+        }
+    }
+
+    pub fn device_clear(&mut self) -> Result<()> {
+        let mut args = XdrWriter::new();
+        args.i32(self.link).u32(0).u32(self.lock_timeout_ms()).u32(self.io_timeout_ms());
+        self.call_generic(DEVICE_CLEAR, args.bytes())
+    }
+
+    pub fn lock(&mut self) -> Result<()> {
+        let mut args = XdrWriter::new();
+        args.i32(self.link).u32(FLAG_WAITLOCK).u32(self.lock_timeout_ms());
+        self.call_generic(DEVICE_LOCK, args.bytes())
+    }
+
+    pub fn unlock(&mut self) -> Result<()> {
+        let mut args = XdrWriter::new();
+        args.i32(self.link);
+        self.call_generic(DEVICE_UNLOCK, args.bytes())
+    }
+
+    pub fn destroy_link(mut self) -> Result<()> {
+        let mut args = XdrWriter::new();
+        args.i32(self.link);
+        let result = self.call_generic(DESTROY_LINK, args.bytes());
+        self.link = -1;
+        result
+    }
+}
+
+impl Drop for Vxi11 {
+    fn drop(&mut self) {
+        if self.link >= 0 {
+            let mut args = XdrWriter::new();
+            args.i32(self.link);
+            let _ = self.call_generic(DESTROY_LINK, args.bytes());
+        }
+    }
+}
+
+impl Visa for Vxi11 {
+    fn write_scip_cmd(&mut self, buf: &[u8]) -> Result<()> {
+        self.device_write(buf)
+    }
+
+    fn read_result(&mut self) -> Result<String> {
+        device::response_text(self.device_read()?)
+    }
+
+    fn read_result2(&mut self) -> Result<String> {
+        self.read_result()
+    }
+
+    fn read_block(&mut self) -> Result<Vec<u8>> {
+        Ok(block::from_message(self.device_read()?)?)
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.device_clear()
+    }
+
+    // Opens a new link to the same core channel; the old one is destroyed on a best
+    // effort basis.
+    fn reconnect(&mut self) -> Result<()> {
+        let address = self.client.stream().peer_addr()?;
+        let stream = resource::connect(&address.ip().to_string(), address.port(), self.timeouts)?;
+        let fresh = Vxi11::create_link(stream, &self.device, self.timeouts)?;
+        drop(std::mem::replace(self, fresh));
+        Ok(())
+    }
+}
+
+// A VXI-11 server backed by the simulator, standing in for a scope in tests. Portmapper
+// and DEVICE_CORE share one TCP port.
+#[cfg(test)]
+pub(crate) mod stand_in {
+    use super::*;
+    use std::io::{BufRead, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use crate::rpc::Call;
+    use crate::simulator::{Instrument, Simulator};
+
+    pub(crate) const MAX_RECV_SIZE: u32 = 1024;
+    const MAX_READ_CHUNK: usize = 4096;
+
+    fn handle(call: &Call, port: u16, simulator: &Mutex<Simulator>) -> Vec<u8> {
+        let mut args = XdrReader::new(call.args);
+        let mut results = XdrWriter::new();
+        let mut simulator = simulator.lock().unwrap();
+        match (call.prog, call.procedure) {
+            (rpc::PORTMAP_PROG, rpc::PMAPPROC_GETPORT) => {
+                let prog = args.u32().unwrap();
+                results.u32(if prog == DEVICE_CORE_PROG { port as u32 } else { 0 });
+            }
+            (DEVICE_CORE_PROG, CREATE_LINK) => {
+                results.u32(0).i32(1).u32(0).u32(MAX_RECV_SIZE);
+            }
+            (DEVICE_CORE_PROG, DEVICE_WRITE) => {
+                let (_link, _io, _lock, _flags) = (args.i32().unwrap(), args.u32().unwrap(), args.u32().unwrap(), args.u32().unwrap());
+                let data = args.opaque().unwrap();
+                simulator.write_all(data).unwrap();
+                results.u32(0).u32(data.len() as u32);
+            }
+            (DEVICE_CORE_PROG, DEVICE_READ) => {
+                let (_link, size) = (args.i32().unwrap(), args.u32().unwrap() as usize);
+                match simulator.fill_buf() {
+                    Ok(pending) => {
+                        let n = pending.len().min(size).min(MAX_READ_CHUNK);
+                        let data = pending[..n].to_vec();
+                        simulator.consume(n);
+                        let end = simulator.fill_buf().is_err();
+                        results.u32(0).u32(if end { REASON_END } else { 1 }).opaque(&data);
+                    }
+                    Err(_) => {
+                        results.u32(15).u32(0).opaque(&[]);
+                    }
+                }
+            }
+            (DEVICE_CORE_PROG, DEVICE_CLEAR) => {
+                while let Ok(pending) = simulator.fill_buf() {
+                    let n = pending.len();
+                    simulator.consume(n);
+                }
+                results.u32(0);
+            }
+            (DEVICE_CORE_PROG, DEVICE_LOCK | DEVICE_UNLOCK | DESTROY_LINK) => {
+                let link = args.i32().unwrap();
+                results.u32(if link == 1 { 0 } else { 4 });
+            }
+            _ => {
+                results.u32(8);
+            }
+        }
+        results.into_bytes()
+    }
+
+    pub(crate) fn spawn(instrument: Instrument) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let simulator = Arc::new(Mutex::new(Simulator::with_instrument(instrument)));
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let simulator = Arc::clone(&simulator);
+                thread::spawn(move || {
+                    while let Ok(Some(msg)) = rpc::read_record(&mut stream) {
+                        let call = rpc::parse_call(&msg).unwrap();
+                        let results = handle(&call, port, &simulator);
+                        if rpc::write_record(&mut stream, &rpc::reply_message(call.xid, &results)).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        port
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::TRIGgerCommand::SWEep;
+    use crate::command::WAVeformCommand::{get_data, ConvertData, Format, Mode};
+    use crate::simulator::Instrument;
+    use crate::Ds1000z;
+
+    fn connect(instrument: Instrument) -> Vxi11 {
+        let port = stand_in::spawn(instrument);
+        Vxi11::connect_via("127.0.0.1", port, "inst0", Timeouts::default()).unwrap()
+    }
+
+    #[test]
+    fn test_query_and_long_write() {
+        let mut device = connect(Instrument::default());
+        assert_eq!(device.max_recv_size, stand_in::MAX_RECV_SIZE);
+        // longer than one device_write
+        let mut command = ":WAV:FORM WORD".to_string();
+        while command.len() < 3000 {
+            command.push_str(";:WAV:FORM WORD");
+        }
+        command.push('\n');
+        device.write_scip_cmd(command.as_bytes()).unwrap();
+        device.write_scip_cmd(b":WAV:FORM?\n").unwrap();
+        assert_eq!(device.read_result().unwrap(), "WORD");
+        assert!(matches!(device.read_result(), Err(Error::Timeout(_))));
+    }
+
+    #[test]
+    fn test_waveform_over_vxi11() {
+        let device = connect(Instrument { memory_depth: Some(30000), ..Instrument::default() });
+        let mut scope = Ds1000z::new(device).unwrap();
+        scope.trigger().sweep(SWEep::SING).unwrap();
+        let mut convert_data = ConvertData::new();
+        let mut waveform = scope.waveform();
+        waveform.format(Format::BYTE).unwrap();
+        waveform.mode(Mode::RAW).unwrap();
+        get_data(30000, &mut waveform, &mut convert_data).unwrap();
+        assert_eq!(convert_data.count, 30000);
+    }
+
+    #[test]
+    fn test_clear_lock_and_destroy() {
+        let mut device = connect(Instrument::default());
+        device.write_scip_cmd(b"*IDN?\n").unwrap();
+        device.device_clear().unwrap();
+        assert!(matches!(device.read_result(), Err(Error::Timeout(_))));
+        device.lock().unwrap();
+        device.unlock().unwrap();
+        device.link = 7;
+        match device.lock() {
+            Err(Error::ProtocolError(msg)) => assert!(msg.contains("invalid link identifier")),
+            other => panic!("unexpected {:?}", other),
+        }
+        device.link = 1;
+        device.destroy_link().unwrap();
+    }
+}